@@ -0,0 +1,93 @@
+//! Writes the values decoded from numeric stimulus ports to a CSV file, one row per value, so
+//! they can be plotted in a spreadsheet.
+
+use crate::{
+    event::{Event, EventKind},
+    rotate::{RotatePolicy, RotatingFile},
+    sink::Sink,
+    timestamp::TimeFormat,
+    warning::{Stoppable, Warnings},
+};
+use std::{io, path::Path};
+
+const HEADER: &str = "host_time,target_time,target_us,port,label,value";
+
+/// Microsecond precision so that closely spaced samples can still be told apart
+const CSV_TIME_FORMAT: &str = "%Y-%m-%d %H:%M:%S%.6f";
+
+/// Writes the `Sample` events it is given and ignores the rest
+pub struct CsvWriter {
+    time_format: TimeFormat,
+    out: Stoppable<RotatingFile>,
+}
+
+impl CsvWriter {
+    /// Appends to the file at `path` if it exists already, writing the header to new files
+    pub fn create<P: AsRef<Path>>(
+        path: P,
+        time_format: TimeFormat,
+        policy: RotatePolicy,
+        warnings: Warnings,
+    ) -> io::Result<CsvWriter> {
+        let path = path.as_ref();
+        let out = RotatingFile::create(path, Some(HEADER), policy, warnings.clone())?;
+        Ok(CsvWriter {
+            time_format,
+            out: Stoppable::new(out, path.display().to_string(), warnings),
+        })
+    }
+}
+
+impl Sink for CsvWriter {
+    fn emit(&mut self, event: &Event) {
+        let (port, label, value) = match event.kind {
+            EventKind::Sample {
+                port,
+                ref label,
+                value,
+            } => (port, label, value),
+            _ => return,
+        };
+        let line = format!(
+            "{},{},{},{},{},{}",
+            event.host_time.format(CSV_TIME_FORMAT),
+            optional(event.target_time),
+            optional(self.time_format.target_micros(event.target_time)),
+            port,
+            escape(label.as_deref().unwrap_or_default()),
+            value
+        );
+        self.out.write(|out| out.write_line(&line));
+    }
+
+    fn flush(&mut self) {
+        self.out.write(RotatingFile::flush);
+    }
+}
+
+fn optional(value: Option<u64>) -> String {
+    value.map(|v| v.to_string()).unwrap_or_default()
+}
+
+// Quotes a field containing a separator, quote or newline as described in RFC 4180
+fn escape(field: &str) -> String {
+    if field.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fields_are_escaped() {
+        assert_eq!(escape("temp"), "temp");
+        assert_eq!(escape(""), "");
+        assert_eq!(escape("x,y"), "\"x,y\"");
+        assert_eq!(escape("a \"b\""), "\"a \"\"b\"\"\"");
+        assert_eq!(escape("two\nlines"), "\"two\nlines\"");
+    }
+}
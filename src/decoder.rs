@@ -0,0 +1,733 @@
+//! Parses the ITM/DWT packet protocol described in the ARMv7-M Architecture Reference Manual,
+//! Appendix D4.2. Unlike the `itm` crate's decoder this understands the hardware source packets generated
+//! by the DWT and keeps partially received packets between reads, so it can be driven by a
+//! non-blocking source.
+
+use std::{
+    fmt,
+    io::{self, Read},
+    mem,
+};
+
+/// Size of the chunks read from the underlying source
+const READ_CHUNK_SIZE: usize = 256;
+
+/// A sync packet is at least 47 zero bits followed by a one bit
+const SYNC_ZERO_BYTES: usize = 5;
+
+/// Discriminator IDs of the hardware source packets
+const EVENT_COUNTER_ID: u8 = 0;
+const EXCEPTION_TRACE_ID: u8 = 1;
+const PC_SAMPLE_ID: u8 = 2;
+
+#[derive(Debug)]
+pub enum DecodeError {
+    /// The source returned an error, including `WouldBlock` when it has no more bytes for now
+    Io(io::Error),
+    /// The source ended between packets
+    Eof,
+    /// The source ended part way through a packet
+    EofDuringPacket,
+    /// Bytes that do not start any known packet, e.g. after a corrupted or dropped byte. They
+    /// are skipped up to the next valid header or sync packet.
+    Malformed(Vec<u8>),
+}
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            DecodeError::Io(e) => write!(f, "IO error: {}", e),
+            DecodeError::Eof => write!(f, "end of file before packet"),
+            DecodeError::EofDuringPacket => write!(f, "end of file during packet"),
+            DecodeError::Malformed(bytes) => {
+                write!(f, "skipped {} bytes with no valid header:", bytes.len())?;
+                for byte in bytes {
+                    write!(f, " {:02x}", byte)?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+/// A software (stimulus port) write
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Instrumentation {
+    port: u8,
+    payload: [u8; 4],
+    len: usize,
+}
+
+impl Instrumentation {
+    pub fn port(&self) -> u8 {
+        self.port
+    }
+
+    pub fn payload(&self) -> &[u8] {
+        &self.payload[..self.len]
+    }
+}
+
+/// What happened to the exception in an exception trace packet
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ExceptionFunction {
+    Entered,
+    Exited,
+    Returned,
+    Reserved,
+}
+
+/// Whether a data trace value packet was generated by a read or a write
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Access {
+    Read,
+    Write,
+}
+
+/// The DWT profiling counters that wrapped, as reported by an event counter packet
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct EventCounter(u8);
+
+impl EventCounter {
+    const NAMES: [(u8, &'static str); 6] = [
+        (1 << 5, "CYC"),
+        (1 << 4, "FOLD"),
+        (1 << 3, "LSU"),
+        (1 << 2, "SLEEP"),
+        (1 << 1, "EXC"),
+        (1 << 0, "CPI"),
+    ];
+
+    /// Names of the counters that wrapped
+    pub fn names(self) -> Vec<&'static str> {
+        EventCounter::NAMES
+            .iter()
+            .filter(|(bit, _)| self.0 & bit != 0)
+            .map(|(_, name)| *name)
+            .collect()
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Packet {
+    Sync,
+    Overflow,
+    /// Ticks since the previous local timestamp. `tc` says how the timestamp relates to the
+    /// packet it follows (0 = in sync, 1 = timestamp delayed, 2 = packet delayed, 3 = both)
+    LocalTimestamp {
+        delta: u32,
+        tc: u8,
+    },
+    /// The low order `bits` bits (at most 26) of the global timestamp
+    GlobalTimestamp1 {
+        value: u32,
+        bits: u8,
+        wrap: bool,
+        clock_change: bool,
+    },
+    /// The high order bits of the global timestamp, starting at bit 26
+    GlobalTimestamp2 {
+        value: u64,
+    },
+    /// `hardware` is the SH bit. For the ITM the value selects the stimulus port page.
+    Extension {
+        hardware: bool,
+        value: u32,
+    },
+    Instrumentation(Instrumentation),
+    EventCounter(EventCounter),
+    ExceptionTrace {
+        number: u16,
+        function: ExceptionFunction,
+    },
+    /// The sampled PC, or `None` if the core was sleeping
+    PcSample {
+        pc: Option<u32>,
+    },
+    DataTracePc {
+        comparator: u8,
+        pc: u32,
+    },
+    /// The low 16 bits of the data address that matched the comparator
+    DataTraceAddress {
+        comparator: u8,
+        address: u16,
+    },
+    DataTraceValue {
+        comparator: u8,
+        access: Access,
+        value: u32,
+        size: usize,
+    },
+    /// A hardware source packet with a discriminator this decoder does not know about
+    Hardware {
+        id: u8,
+        payload: [u8; 4],
+        size: usize,
+    },
+}
+
+/// The name of an exception number as found in an exception trace packet
+pub fn exception_name(number: u16) -> String {
+    match number {
+        0 => "Thread".to_string(),
+        1 => "Reset".to_string(),
+        2 => "NMI".to_string(),
+        3 => "HardFault".to_string(),
+        4 => "MemManage".to_string(),
+        5 => "BusFault".to_string(),
+        6 => "UsageFault".to_string(),
+        11 => "SVCall".to_string(),
+        12 => "DebugMonitor".to_string(),
+        14 => "PendSV".to_string(),
+        15 => "SysTick".to_string(),
+        n if n >= 16 => format!("IRQ{}", n - 16),
+        n => format!("Reserved{}", n),
+    }
+}
+
+enum Parsed {
+    Packet(Packet, usize),
+    /// Bytes that can be dropped without producing a packet
+    Skip(usize),
+    /// More bytes are needed to complete the packet
+    Incomplete,
+    /// The first byte cannot start a packet
+    Invalid,
+}
+
+/// Reads a payload where each byte holds 7 bits and the top bit says whether another byte
+/// follows. Returns the value, the number of bytes used and the last byte.
+fn continued(buf: &[u8], max: usize) -> Option<(u64, usize, u8)> {
+    let mut value = 0u64;
+    for (i, &b) in buf.iter().take(max).enumerate() {
+        value |= u64::from(b & 0x7f) << (7 * i);
+        if b & 0x80 == 0 || i + 1 == max {
+            return Some((value, i + 1, b));
+        }
+    }
+    None
+}
+
+fn little_endian(payload: &[u8]) -> u32 {
+    payload
+        .iter()
+        .rev()
+        .fold(0, |value, &b| (value << 8) | u32::from(b))
+}
+
+fn parse(buf: &[u8], page: u8) -> Parsed {
+    let header = buf[0];
+    match header {
+        0x00 => match buf.iter().position(|&b| b != 0) {
+            Some(zeros) if zeros >= SYNC_ZERO_BYTES && buf[zeros] == 0x80 => {
+                Parsed::Packet(Packet::Sync, zeros + 1)
+            }
+            Some(_) => Parsed::Invalid,
+            // keep just enough zeros to recognise the sync packet they may be part of
+            None if buf.len() > SYNC_ZERO_BYTES => Parsed::Skip(buf.len() - SYNC_ZERO_BYTES),
+            None => Parsed::Incomplete,
+        },
+        0x70 => Parsed::Packet(Packet::Overflow, 1),
+        h if h & 0x0f == 0 && h & 0x80 == 0 => Parsed::Packet(
+            Packet::LocalTimestamp {
+                delta: u32::from((h >> 4) & 0x07),
+                tc: 0,
+            },
+            1,
+        ),
+        h if h & 0x0f == 0 && h & 0xc0 == 0xc0 => match continued(&buf[1..], 4) {
+            Some((delta, len, _)) => Parsed::Packet(
+                Packet::LocalTimestamp {
+                    delta: delta as u32,
+                    tc: (h >> 4) & 0x03,
+                },
+                1 + len,
+            ),
+            None => Parsed::Incomplete,
+        },
+        0x94 => match continued(&buf[1..], 4) {
+            Some((value, len, last)) => {
+                let (value, bits, wrap, clock_change) = if len == 4 {
+                    // the last byte only holds 5 bits of the timestamp followed by two flags
+                    (value & 0x3ff_ffff, 26, last & 0x40 != 0, last & 0x20 != 0)
+                } else {
+                    (value, 7 * len as u8, false, false)
+                };
+                Parsed::Packet(
+                    Packet::GlobalTimestamp1 {
+                        value: value as u32,
+                        bits,
+                        wrap,
+                        clock_change,
+                    },
+                    1 + len,
+                )
+            }
+            None => Parsed::Incomplete,
+        },
+        0xb4 => match continued(&buf[1..], 6) {
+            Some((value, len, _)) => Parsed::Packet(Packet::GlobalTimestamp2 { value }, 1 + len),
+            None => Parsed::Incomplete,
+        },
+        h if h & 0x0b == 0x08 => {
+            let hardware = h & 0x04 != 0;
+            let ex = u32::from((h >> 4) & 0x07);
+            if h & 0x80 == 0 {
+                return Parsed::Packet(
+                    Packet::Extension {
+                        hardware,
+                        value: ex,
+                    },
+                    1,
+                );
+            }
+
+            match continued(&buf[1..], 4) {
+                Some((value, len, _)) => Parsed::Packet(
+                    Packet::Extension {
+                        hardware,
+                        value: ex | (value as u32) << 3,
+                    },
+                    1 + len,
+                ),
+                None => Parsed::Incomplete,
+            }
+        }
+        h if h & 0x03 != 0 => {
+            let size = match h & 0x03 {
+                0b01 => 1,
+                0b10 => 2,
+                _ => 4,
+            };
+            if buf.len() < 1 + size {
+                return Parsed::Incomplete;
+            }
+
+            let mut payload = [0; 4];
+            payload[..size].copy_from_slice(&buf[1..=size]);
+            let address = h >> 3;
+            let packet = if h & 0x04 == 0 {
+                Packet::Instrumentation(Instrumentation {
+                    port: page.wrapping_mul(32).wrapping_add(address),
+                    payload,
+                    len: size,
+                })
+            } else {
+                hardware_packet(address, payload, size)
+            };
+            Parsed::Packet(packet, 1 + size)
+        }
+        _ => Parsed::Invalid,
+    }
+}
+
+fn hardware_packet(id: u8, payload: [u8; 4], size: usize) -> Packet {
+    let value = little_endian(&payload[..size]);
+    match (id, size) {
+        (EVENT_COUNTER_ID, 1) => Packet::EventCounter(EventCounter(payload[0])),
+        (EXCEPTION_TRACE_ID, 2) => Packet::ExceptionTrace {
+            number: (value & 0x1ff) as u16,
+            function: match (value >> 12) & 0x03 {
+                0b01 => ExceptionFunction::Entered,
+                0b10 => ExceptionFunction::Exited,
+                0b11 => ExceptionFunction::Returned,
+                _ => ExceptionFunction::Reserved,
+            },
+        },
+        (PC_SAMPLE_ID, 4) => Packet::PcSample { pc: Some(value) },
+        (PC_SAMPLE_ID, 1) => Packet::PcSample { pc: None },
+        // discriminator 0b01_CC_A: PC value (A = 0) or data address (A = 1) for comparator CC
+        (8..=15, 4) if id & 0x01 == 0 => Packet::DataTracePc {
+            comparator: (id >> 1) & 0x03,
+            pc: value,
+        },
+        (8..=15, 2) if id & 0x01 == 1 => Packet::DataTraceAddress {
+            comparator: (id >> 1) & 0x03,
+            address: value as u16,
+        },
+        // discriminator 0b10_CC_W: data value read (W = 0) or written (W = 1) by comparator CC
+        (16..=23, _) => Packet::DataTraceValue {
+            comparator: (id >> 1) & 0x03,
+            access: if id & 0x01 == 0 {
+                Access::Read
+            } else {
+                Access::Write
+            },
+            value,
+            size,
+        },
+        _ => Packet::Hardware { id, payload, size },
+    }
+}
+
+/// Parses ITM packets from a byte source
+pub struct Decoder<R: Read> {
+    inner: R,
+    buf: Vec<u8>,
+    // the stimulus port page selected by the last ITM extension packet
+    page: u8,
+    bytes_read: u64,
+}
+
+impl<R: Read> Decoder<R> {
+    pub fn new(inner: R) -> Decoder<R> {
+        Decoder {
+            inner,
+            buf: Vec::new(),
+            page: 0,
+            bytes_read: 0,
+        }
+    }
+
+    /// The number of bytes read from the source since the last call
+    pub fn take_bytes_read(&mut self) -> u64 {
+        mem::take(&mut self.bytes_read)
+    }
+
+    /// Reads the next packet. If the source has no more bytes for now (`WouldBlock`) any
+    /// partial packet is kept and completed by a later call.
+    pub fn read_packet(&mut self) -> Result<Packet, DecodeError> {
+        loop {
+            if !self.buf.is_empty() {
+                match parse(&self.buf, self.page) {
+                    Parsed::Packet(packet, len) => {
+                        self.buf.drain(..len);
+                        if let Packet::Extension {
+                            hardware: false,
+                            value,
+                        } = packet
+                        {
+                            self.page = value as u8;
+                        }
+                        return Ok(packet);
+                    }
+                    Parsed::Skip(len) => {
+                        self.buf.drain(..len);
+                        continue;
+                    }
+                    Parsed::Invalid => return Err(DecodeError::Malformed(self.resync())),
+                    Parsed::Incomplete => (),
+                }
+            }
+
+            let mut chunk = [0; READ_CHUNK_SIZE];
+            match self.inner.read(&mut chunk) {
+                Ok(0) if self.buf.is_empty() => return Err(DecodeError::Eof),
+                Ok(0) => return Err(DecodeError::EofDuringPacket),
+                Ok(n) => {
+                    self.bytes_read += n as u64;
+                    self.buf.extend_from_slice(&chunk[..n]);
+                }
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => (),
+                Err(e) => return Err(DecodeError::Io(e)),
+            }
+        }
+    }
+
+    /// Drops the bytes at the start of the buffer up to the next one that can start a packet, so
+    /// that a run of garbage is reported as a single error
+    fn resync(&mut self) -> Vec<u8> {
+        let mut skipped = 1;
+        while skipped < self.buf.len() {
+            match parse(&self.buf[skipped..], self.page) {
+                Parsed::Invalid => skipped += 1,
+                _ => break,
+            }
+        }
+        self.buf.drain(..skipped).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::VecDeque;
+
+    /// Hands out the chunks one read at a time, with `None` standing for `WouldBlock`
+    struct Chunks(VecDeque<Option<Vec<u8>>>);
+
+    impl Read for Chunks {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            match self.0.pop_front() {
+                Some(Some(chunk)) => {
+                    buf[..chunk.len()].copy_from_slice(&chunk);
+                    Ok(chunk.len())
+                }
+                Some(None) => Err(io::ErrorKind::WouldBlock.into()),
+                None => Ok(0),
+            }
+        }
+    }
+
+    fn decode(bytes: &[u8]) -> Vec<Packet> {
+        let mut decoder = Decoder::new(bytes);
+        let mut packets = Vec::new();
+        loop {
+            match decoder.read_packet() {
+                Ok(p) => packets.push(p),
+                Err(DecodeError::Eof) => return packets,
+                Err(e) => panic!("Unexpected error: {}", e),
+            }
+        }
+    }
+
+    fn decode_one(bytes: &[u8]) -> Packet {
+        let packets = decode(bytes);
+        assert_eq!(packets.len(), 1, "{:?}", packets);
+        packets[0]
+    }
+
+    fn instrumentation(port: u8, bytes: &[u8]) -> Packet {
+        let mut payload = [0; 4];
+        payload[..bytes.len()].copy_from_slice(bytes);
+        Packet::Instrumentation(Instrumentation {
+            port,
+            payload,
+            len: bytes.len(),
+        })
+    }
+
+    #[test]
+    fn sync() {
+        assert_eq!(decode_one(&[0, 0, 0, 0, 0, 0x80]), Packet::Sync);
+        // more zeros than a sync needs, split over several reads
+        let mut bytes = vec![0; 20];
+        bytes.push(0x80);
+        let chunks = bytes.chunks(3).map(|c| Some(c.to_vec())).collect();
+        let mut decoder = Decoder::new(Chunks(chunks));
+        assert_eq!(decoder.read_packet().unwrap(), Packet::Sync);
+    }
+
+    #[test]
+    fn overflow() {
+        assert_eq!(decode_one(&[0x70]), Packet::Overflow);
+    }
+
+    #[test]
+    fn local_timestamp() {
+        // format 2: the delta is held in the header
+        assert_eq!(
+            decode_one(&[0x30]),
+            Packet::LocalTimestamp { delta: 3, tc: 0 }
+        );
+        // format 1: a continued delta and the TC bits in the header
+        assert_eq!(
+            decode_one(&[0xc0, 0x64]),
+            Packet::LocalTimestamp { delta: 100, tc: 0 }
+        );
+        assert_eq!(
+            decode_one(&[0xd0, 0x81, 0x01]),
+            Packet::LocalTimestamp { delta: 129, tc: 1 }
+        );
+        assert_eq!(
+            decode_one(&[0xf0, 0x80, 0x80, 0x80, 0x01]),
+            Packet::LocalTimestamp {
+                delta: 1 << 21,
+                tc: 3
+            }
+        );
+    }
+
+    #[test]
+    fn global_timestamp() {
+        assert_eq!(
+            decode_one(&[0x94, 0x05]),
+            Packet::GlobalTimestamp1 {
+                value: 5,
+                bits: 7,
+                wrap: false,
+                clock_change: false
+            }
+        );
+        // a full GTS1 has the wrap and clock change flags above bit 25
+        assert_eq!(
+            decode_one(&[0x94, 0x81, 0x80, 0x80, 0x61]),
+            Packet::GlobalTimestamp1 {
+                value: 1 | 1 << 21,
+                bits: 26,
+                wrap: true,
+                clock_change: true
+            }
+        );
+        assert_eq!(
+            decode_one(&[0x94, 0x80, 0x80, 0x80, 0x01]),
+            Packet::GlobalTimestamp1 {
+                value: 1 << 21,
+                bits: 26,
+                wrap: false,
+                clock_change: false
+            }
+        );
+        assert_eq!(
+            decode_one(&[0xb4, 0x81, 0x01]),
+            Packet::GlobalTimestamp2 { value: 129 }
+        );
+    }
+
+    #[test]
+    fn extension_selects_stimulus_page() {
+        assert_eq!(
+            decode(&[0x01, b'a', 0x18, 0x01, b'b', 0x08, 0x09, b'c']),
+            vec![
+                instrumentation(0, b"a"),
+                Packet::Extension {
+                    hardware: false,
+                    value: 1
+                },
+                instrumentation(32, b"b"),
+                Packet::Extension {
+                    hardware: false,
+                    value: 0
+                },
+                instrumentation(1, b"c"),
+            ]
+        );
+        assert_eq!(
+            decode_one(&[0x8c, 0x01]),
+            Packet::Extension {
+                hardware: true,
+                value: 8
+            }
+        );
+    }
+
+    #[test]
+    fn instrumentation_sizes() {
+        assert_eq!(decode_one(&[0x09, 1]), instrumentation(1, &[1]));
+        assert_eq!(decode_one(&[0x0a, 1, 2]), instrumentation(1, &[1, 2]));
+        assert_eq!(
+            decode_one(&[0xfb, 1, 2, 3, 4]),
+            instrumentation(31, &[1, 2, 3, 4])
+        );
+    }
+
+    #[test]
+    fn hardware_packets() {
+        match decode_one(&[0x05, 0x21]) {
+            Packet::EventCounter(counter) => assert_eq!(counter.names(), vec!["CYC", "CPI"]),
+            p => panic!("Unexpected packet: {:?}", p),
+        }
+        assert_eq!(
+            decode_one(&[0x0e, 0x13, 0x10]),
+            Packet::ExceptionTrace {
+                number: 19,
+                function: ExceptionFunction::Entered
+            }
+        );
+        assert_eq!(
+            decode_one(&[0x0e, 0x0f, 0x20]),
+            Packet::ExceptionTrace {
+                number: 15,
+                function: ExceptionFunction::Exited
+            }
+        );
+        assert_eq!(
+            decode_one(&[0x17, 0x78, 0x56, 0x34, 0x12]),
+            Packet::PcSample {
+                pc: Some(0x1234_5678)
+            }
+        );
+        assert_eq!(decode_one(&[0x15, 0x00]), Packet::PcSample { pc: None });
+        assert_eq!(
+            decode_one(&[0x57, 0x78, 0x56, 0x34, 0x12]),
+            Packet::DataTracePc {
+                comparator: 1,
+                pc: 0x1234_5678
+            }
+        );
+        assert_eq!(
+            decode_one(&[0x5e, 0x34, 0x12]),
+            Packet::DataTraceAddress {
+                comparator: 1,
+                address: 0x1234
+            }
+        );
+        assert_eq!(
+            decode_one(&[0x9d, 0x42]),
+            Packet::DataTraceValue {
+                comparator: 1,
+                access: Access::Write,
+                value: 0x42,
+                size: 1
+            }
+        );
+        assert_eq!(
+            decode_one(&[0xa6, 0x34, 0x12]),
+            Packet::DataTraceValue {
+                comparator: 2,
+                access: Access::Read,
+                value: 0x1234,
+                size: 2
+            }
+        );
+        assert_eq!(
+            decode_one(&[0x1d, 0x42]),
+            Packet::Hardware {
+                id: 3,
+                payload: [0x42, 0, 0, 0],
+                size: 1
+            }
+        );
+    }
+
+    #[test]
+    fn partial_packet_carried_over_would_block() {
+        let chunks = vec![
+            Some(vec![0x01, b'a', 0x03, 1, 2]),
+            None,
+            Some(vec![3, 4, 0xc0]),
+            None,
+            Some(vec![0x64]),
+        ];
+        let mut decoder = Decoder::new(Chunks(chunks.into_iter().collect()));
+        assert_eq!(decoder.read_packet().unwrap(), instrumentation(0, b"a"));
+        match decoder.read_packet() {
+            Err(DecodeError::Io(ref e)) if e.kind() == io::ErrorKind::WouldBlock => (),
+            r => panic!("Unexpected result: {:?}", r),
+        }
+        assert_eq!(
+            decoder.read_packet().unwrap(),
+            instrumentation(0, &[1, 2, 3, 4])
+        );
+        assert!(decoder.read_packet().is_err());
+        assert_eq!(
+            decoder.read_packet().unwrap(),
+            Packet::LocalTimestamp { delta: 100, tc: 0 }
+        );
+        match decoder.read_packet() {
+            Err(DecodeError::Eof) => (),
+            r => panic!("Unexpected result: {:?}", r),
+        }
+        assert_eq!(decoder.take_bytes_read(), 9);
+    }
+
+    #[test]
+    fn end_of_input() {
+        match Decoder::new(&[][..]).read_packet() {
+            Err(DecodeError::Eof) => (),
+            r => panic!("Unexpected result: {:?}", r),
+        }
+        let mut decoder = Decoder::new(&[0x03, 1][..]);
+        match decoder.read_packet() {
+            Err(DecodeError::EofDuringPacket) => (),
+            r => panic!("Unexpected result: {:?}", r),
+        }
+    }
+
+    #[test]
+    fn malformed_bytes_resync_as_one_error() {
+        let mut decoder = Decoder::new(&[0x04, 0x80, 0x04, 0x01, b'x', 0, 0, 0x01, b'y'][..]);
+        match decoder.read_packet() {
+            Err(DecodeError::Malformed(bytes)) => assert_eq!(bytes, vec![0x04, 0x80, 0x04]),
+            r => panic!("Unexpected result: {:?}", r),
+        }
+        assert_eq!(decoder.read_packet().unwrap(), instrumentation(0, b"x"));
+        // zeros too few to be a sync
+        match decoder.read_packet() {
+            Err(DecodeError::Malformed(bytes)) => assert_eq!(bytes, vec![0, 0]),
+            r => panic!("Unexpected result: {:?}", r),
+        }
+        assert_eq!(decoder.read_packet().unwrap(), instrumentation(0, b"y"));
+    }
+}
@@ -0,0 +1,154 @@
+use crate::{
+    decoder::{Access, EventCounter, ExceptionFunction, Packet},
+    numeric::Sample,
+    stats::Summary,
+};
+use chrono::{DateTime, Local};
+
+/// Something decoded from the ITM stream, stamped with the host and target time it happened at
+#[derive(Clone, Debug, PartialEq)]
+pub struct Event {
+    pub host_time: DateTime<Local>,
+    /// Target clock ticks, if the target has sent any timestamp packets
+    pub target_time: Option<u64>,
+    pub kind: EventKind,
+}
+
+/// The kinds of event, more of which may be added without a breaking change
+#[derive(Clone, Debug, PartialEq)]
+#[non_exhaustive]
+pub enum EventKind {
+    /// A line of text written to a stimulus port
+    Line {
+        port: u8,
+        label: Option<String>,
+        text: String,
+    },
+    /// A value written to a stimulus port configured with a numeric data type
+    Sample {
+        port: u8,
+        label: Option<String>,
+        value: Sample,
+    },
+    /// Bytes written to a stimulus port that are not valid UTF-8
+    InvalidPayload {
+        port: u8,
+        bytes: Vec<u8>,
+    },
+    Exception {
+        number: u16,
+        function: ExceptionFunction,
+    },
+    PcSample {
+        pc: Option<u32>,
+    },
+    DataTracePc {
+        comparator: u8,
+        pc: u32,
+    },
+    DataTraceAddress {
+        comparator: u8,
+        address: u16,
+    },
+    DataTraceValue {
+        comparator: u8,
+        access: Access,
+        value: u32,
+        size: usize,
+    },
+    EventCounter(EventCounter),
+    Overflow,
+    /// Bytes the decoder could not make sense of, or a packet cut short by the end of the input
+    DecodeError {
+        message: String,
+        skipped: usize,
+    },
+    /// A local or global timestamp packet updated the target clock. The global timestamp counts
+    /// a different clock to the target time so it is given separately.
+    Timestamp {
+        global: Option<u64>,
+    },
+    /// The input was lost (`connected` is false) or came back
+    Connection {
+        name: String,
+        connected: bool,
+    },
+    /// Something that went wrong without stopping decoding, e.g. an output that could no longer
+    /// be written (see [`Warnings`](crate::warning::Warnings))
+    Warning {
+        message: String,
+    },
+    /// The statistics, given every `--stats-interval` (see [`Stats`](crate::stats::Stats))
+    Stats(Summary),
+    /// A packet with no event of its own, e.g. a write to a port that is not being decoded
+    Other(Packet),
+}
+
+impl EventKind {
+    /// The stimulus port the event was written to, if any
+    pub fn port(&self) -> Option<u8> {
+        match *self {
+            EventKind::Line { port, .. }
+            | EventKind::Sample { port, .. }
+            | EventKind::InvalidPayload { port, .. } => Some(port),
+            _ => None,
+        }
+    }
+
+    pub fn label(&self) -> Option<&str> {
+        match *self {
+            EventKind::Line { ref label, .. } | EventKind::Sample { ref label, .. } => {
+                label.as_deref()
+            }
+            _ => None,
+        }
+    }
+
+    /// The event kinds for hardware source packets generated by the DWT
+    pub fn from_hardware_packet(p: &Packet) -> Option<EventKind> {
+        let kind = match *p {
+            Packet::ExceptionTrace { number, function } => {
+                EventKind::Exception { number, function }
+            }
+            Packet::PcSample { pc } => EventKind::PcSample { pc },
+            Packet::DataTracePc { comparator, pc } => EventKind::DataTracePc { comparator, pc },
+            Packet::DataTraceAddress {
+                comparator,
+                address,
+            } => EventKind::DataTraceAddress {
+                comparator,
+                address,
+            },
+            Packet::DataTraceValue {
+                comparator,
+                access,
+                value,
+                size,
+            } => EventKind::DataTraceValue {
+                comparator,
+                access,
+                value,
+                size,
+            },
+            Packet::EventCounter(counter) => EventKind::EventCounter(counter),
+            _ => return None,
+        };
+        Some(kind)
+    }
+}
+
+pub fn function_name(function: ExceptionFunction) -> &'static str {
+    match function {
+        ExceptionFunction::Entered => "entered",
+        ExceptionFunction::Exited => "exited",
+        ExceptionFunction::Returned => "returned",
+        ExceptionFunction::Reserved => "reserved",
+    }
+}
+
+pub fn access_name(access: Access) -> &'static str {
+    match access {
+        Access::Read => "read",
+        Access::Write => "write",
+    }
+}
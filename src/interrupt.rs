@@ -0,0 +1,76 @@
+//! Turns a request to stop (e.g. Ctrl-C) into a mio event so that the poll loop can end the
+//! session cleanly instead of the process being killed part way through.
+
+use mio::{Evented, Poll, PollOpt, Ready, Registration, SetReadiness, Token};
+use std::{
+    io,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+};
+
+/// Register with `stream::INTERRUPT_TOKEN` to stop streaming when the handle is triggered
+pub struct Interrupt {
+    registration: Registration,
+    // for code that is not waiting on the poll, e.g. decoding a file or waiting to reconnect
+    flag: Arc<AtomicBool>,
+}
+
+/// Triggers an `Interrupt`, e.g. from a signal handler or another thread
+#[derive(Clone)]
+pub struct InterruptHandle {
+    set_readiness: SetReadiness,
+    flag: Arc<AtomicBool>,
+}
+
+impl Interrupt {
+    pub fn new() -> (Interrupt, InterruptHandle) {
+        let (registration, set_readiness) = Registration::new2();
+        let flag = Arc::new(AtomicBool::new(false));
+        let handle = InterruptHandle {
+            set_readiness,
+            flag: flag.clone(),
+        };
+        (Interrupt { registration, flag }, handle)
+    }
+
+    pub fn is_set(&self) -> bool {
+        self.flag.load(Ordering::SeqCst)
+    }
+}
+
+impl InterruptHandle {
+    /// Returns true if the interrupt had already been triggered
+    pub fn interrupt(&self) -> bool {
+        let already = self.flag.swap(true, Ordering::SeqCst);
+        let _ = self.set_readiness.set_readiness(Ready::readable());
+        already
+    }
+}
+
+impl Evented for Interrupt {
+    fn register(
+        &self,
+        poll: &Poll,
+        token: Token,
+        interest: Ready,
+        opts: PollOpt,
+    ) -> io::Result<()> {
+        self.registration.register(poll, token, interest, opts)
+    }
+
+    fn reregister(
+        &self,
+        poll: &Poll,
+        token: Token,
+        interest: Ready,
+        opts: PollOpt,
+    ) -> io::Result<()> {
+        self.registration.reregister(poll, token, interest, opts)
+    }
+
+    fn deregister(&self, poll: &Poll) -> io::Result<()> {
+        poll.deregister(&self.registration)
+    }
+}
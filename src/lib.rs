@@ -0,0 +1,62 @@
+//! Decodes the ITM/DWT trace stream of ARM Cortex-M microcontrollers, as read from an SWO
+//! serial port, a debug server or a capture file.
+//!
+//! A [`Decoder`](decoder::Decoder) turns the bytes read from a [`Source`](source::Source) into
+//! packets, a [`Session`](session::Session) turns the packets into [`Event`](event::Event)s
+//! (assembling the text written to each stimulus port into lines) and hands them to a
+//! [`Sink`](sink::Sink). The functions in [`stream`] drive a source with mio until it closes.
+
+use std::{fmt, io};
+
+pub mod csv;
+pub mod decoder;
+pub mod event;
+pub mod interrupt;
+pub mod line;
+pub mod numeric;
+pub mod output;
+pub mod port_log;
+pub mod record;
+pub mod replay;
+pub mod rotate;
+pub mod serial;
+pub mod session;
+pub mod sink;
+pub mod source;
+pub mod stats;
+pub mod stream;
+pub mod timestamp;
+pub mod tpiu;
+pub mod warning;
+
+#[derive(Debug)]
+pub enum Error {
+    Poll(io::Error),
+    /// The source closed or failed to read
+    PortClosed,
+    /// Stopped by an [`Interrupt`](interrupt::Interrupt), e.g. on Ctrl-C
+    Interrupted,
+    /// The output can take no more, e.g. stdout piped into `head`
+    OutputClosed,
+    Io(io::Error),
+}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Error {
+        Error::Io(e)
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Poll(e) => write!(f, "Poll error: {}", e),
+            Error::PortClosed => write!(f, "Port closed"),
+            Error::Interrupted => write!(f, "Interrupted"),
+            Error::OutputClosed => write!(f, "Output closed"),
+            Error::Io(e) => write!(f, "IO error: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
@@ -0,0 +1,230 @@
+use chrono::{DateTime, Local};
+use std::{
+    str,
+    time::{Duration, Instant},
+};
+
+/// Text decoded from one or more payloads
+#[derive(Default)]
+pub struct Decoded {
+    pub text: String,
+    /// Bytes that are not valid UTF-8 (always empty in lossy mode)
+    pub invalid: Vec<u8>,
+}
+
+/// Decodes the UTF-8 written to one stimulus port, carrying a multibyte character that
+/// straddles two ITM writes over to the next payload instead of rejecting both halves
+pub struct Utf8Decoder {
+    pending: Vec<u8>,
+    lossy: bool,
+}
+
+impl Utf8Decoder {
+    /// In `lossy` mode invalid bytes are replaced with U+FFFD rather than returned
+    pub fn new(lossy: bool) -> Utf8Decoder {
+        Utf8Decoder {
+            pending: Vec::new(),
+            lossy,
+        }
+    }
+
+    /// Drops an incomplete trailing sequence, e.g. after the input was reconnected
+    pub fn reset(&mut self) {
+        self.pending.clear();
+    }
+
+    pub fn push(&mut self, payload: &[u8]) -> Decoded {
+        self.pending.extend_from_slice(payload);
+        let mut decoded = Decoded::default();
+        loop {
+            let (valid, invalid_len) = match str::from_utf8(&self.pending) {
+                Ok(s) => {
+                    decoded.text.push_str(s);
+                    self.pending.clear();
+                    return decoded;
+                }
+                Err(e) => (e.valid_up_to(), e.error_len()),
+            };
+
+            decoded
+                .text
+                .push_str(str::from_utf8(&self.pending[..valid]).unwrap());
+            match invalid_len {
+                Some(len) => {
+                    if self.lossy {
+                        decoded.text.push(char::REPLACEMENT_CHARACTER);
+                    } else {
+                        decoded
+                            .invalid
+                            .extend_from_slice(&self.pending[valid..valid + len]);
+                    }
+                    self.pending.drain(..valid + len);
+                }
+                None => {
+                    // an incomplete sequence at the end, wait for the rest of it
+                    self.pending.drain(..valid);
+                    return decoded;
+                }
+            }
+        }
+    }
+}
+
+/// A line of text decoded from a single stimulus port
+pub struct Line {
+    /// Host time at which the first byte of the line arrived
+    pub time: DateTime<Local>,
+    /// Target clock ticks at which the first byte of the line arrived, if known
+    pub target: Option<u64>,
+    pub text: String,
+}
+
+struct Started {
+    time: DateTime<Local>,
+    instant: Instant,
+    target: Option<u64>,
+}
+
+/// Collects the text written to one stimulus port and only hands out whole lines so that
+/// ports written from different interrupts cannot splice their output together
+#[derive(Default)]
+pub struct LineAssembler {
+    text: String,
+    started: Option<Started>,
+}
+
+impl LineAssembler {
+    /// Appends decoded text and returns every line completed by it. `target` is the current
+    /// target clock reading.
+    pub fn push(&mut self, s: &str, target: Option<u64>) -> Vec<Line> {
+        let mut lines = Vec::new();
+        for (i, part) in s.split('\n').enumerate() {
+            if i > 0 {
+                lines.push(self.take(target));
+            }
+
+            if !part.is_empty() {
+                if self.started.is_none() {
+                    self.started = Some(Started {
+                        time: Local::now(),
+                        instant: Instant::now(),
+                        target,
+                    });
+                }
+                self.text.push_str(part);
+            }
+        }
+        lines
+    }
+
+    /// Returns the partial line if it has been waiting for its newline for longer than `timeout`
+    pub fn flush_expired(&mut self, timeout: Duration) -> Option<Line> {
+        match self.started {
+            Some(ref started) if started.instant.elapsed() >= timeout => Some(self.take(None)),
+            _ => None,
+        }
+    }
+
+    /// Returns the partial line, if any, regardless of how long it has been waiting
+    pub fn flush(&mut self) -> Option<Line> {
+        if self.started.is_some() {
+            Some(self.take(None))
+        } else {
+            None
+        }
+    }
+
+    /// How long until the partial line should be flushed, if there is one
+    pub fn time_left(&self, timeout: Duration) -> Option<Duration> {
+        self.started.as_ref().map(|started| {
+            timeout
+                .checked_sub(started.instant.elapsed())
+                .unwrap_or_default()
+        })
+    }
+
+    fn take(&mut self, target: Option<u64>) -> Line {
+        let (time, target) = match self.started.take() {
+            Some(started) => (started.time, started.target),
+            // an empty line has no first byte so it is stamped when the newline arrives
+            None => (Local::now(), target),
+        };
+
+        Line {
+            time,
+            target,
+            text: ::std::mem::take(&mut self.text),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn decode(decoder: &mut Utf8Decoder, payloads: &[&[u8]]) -> (String, Vec<u8>) {
+        let mut text = String::new();
+        let mut invalid = Vec::new();
+        for payload in payloads {
+            let decoded = decoder.push(payload);
+            text.push_str(&decoded.text);
+            invalid.extend(decoded.invalid);
+        }
+        (text, invalid)
+    }
+
+    #[test]
+    fn character_split_across_payloads() {
+        let text = "25.5°C 3µs 😀";
+        let bytes = text.as_bytes();
+        for size in [1, 2, 4].iter() {
+            let payloads = bytes.chunks(*size).collect::<Vec<_>>();
+            let mut decoder = Utf8Decoder::new(false);
+            assert_eq!(decode(&mut decoder, &payloads), (text.to_string(), vec![]));
+        }
+    }
+
+    #[test]
+    fn partial_character_is_held_back() {
+        let mut decoder = Utf8Decoder::new(false);
+        let decoded = decoder.push(&[b'a', 0xf0, 0x9f]);
+        assert_eq!(decoded.text, "a");
+        assert!(decoded.invalid.is_empty());
+        let decoded = decoder.push(&[0x98, 0x80]);
+        assert_eq!(decoded.text, "😀");
+    }
+
+    #[test]
+    fn invalid_bytes_are_returned() {
+        let mut decoder = Utf8Decoder::new(false);
+        assert_eq!(
+            decode(&mut decoder, &[&[b'a', 0xff], &[b'b', 0x80, b'c']]),
+            ("abc".to_string(), vec![0xff, 0x80])
+        );
+        // a lead byte followed by something that doesn't continue it
+        assert_eq!(
+            decode(&mut decoder, &[&[0xc2], b"d"]),
+            ("d".to_string(), vec![0xc2])
+        );
+    }
+
+    #[test]
+    fn invalid_bytes_are_replaced_when_lossy() {
+        let mut decoder = Utf8Decoder::new(true);
+        assert_eq!(
+            decode(&mut decoder, &[&[b'a', 0xff], &[b'b', 0xc2], b"c"]),
+            ("a\u{fffd}b\u{fffd}c".to_string(), vec![])
+        );
+    }
+
+    #[test]
+    fn reset_drops_partial_character() {
+        let mut decoder = Utf8Decoder::new(false);
+        assert_eq!(decoder.push(&[0xc2]).text, "");
+        decoder.reset();
+        assert_eq!(
+            decode(&mut decoder, &[&[0xb5, b'x']]),
+            ("x".to_string(), vec![0xb5])
+        );
+    }
+}
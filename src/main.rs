@@ -1,94 +1,193 @@
 extern crate clap;
-use std::str;
 
-extern crate chrono;
-use chrono::Local;
+use clap::{App, AppSettings, Arg, ArgGroup, ArgMatches, SubCommand};
+use mio::{Poll, PollOpt, Ready};
+use serialitm::{
+    csv::CsvWriter,
+    event::{Event, EventKind},
+    interrupt::Interrupt,
+    output::{FileSink, Formatter, OutputFormat, StdoutSink},
+    port_log::{self, PortLogs},
+    record::{RecordFormat, Recorder, Tee},
+    rotate::{self, RotatePolicy},
+    serial,
+    session::{Ports, Session, NUM_STIMULUS_PORTS},
+    sink::{Filtered, Sink, SinkKind, SinkSpec, Sinks},
+    source::{self, Source, SourceUri},
+    stats::Stats,
+    stream::{self, replay_file, stream_packets, stream_with_reconnect},
+    timestamp::{HostTime, TimeFormat, TimeSource},
+    tpiu::{self, SourceFiles, TpiuOptions},
+    warning::Warnings,
+    Error,
+};
+use serialport::{SerialPortInfo, SerialPortType};
+use std::{
+    cell::RefCell,
+    io::{self, Write},
+    rc::Rc,
+    time::Duration,
+};
 
-use clap::{App, AppSettings, Arg};
-use itm::{packet, packet::Packet, Decoder};
-use std::{io::Error as StdError, time::Duration};
-
-#[cfg(unix)]
-use mio::unix::UnixReady;
-use mio::{Events, Poll, PollOpt, Ready, Token};
+// The factor to divide the recorded delays by, or `None` to replay as fast as possible
+fn parse_replay_speed(s: &str) -> Result<Option<f64>, String> {
+    if s == "max" {
+        return Ok(None);
+    }
 
-const SERIAL_TOKEN: Token = Token(0);
+    match s.parse::<f64>() {
+        Ok(speed) if speed > 0.0 && speed.is_finite() => Ok(Some(speed)),
+        Ok(_) => Err("The replay speed must be greater than 0".to_string()),
+        Err(e) => Err(e.to_string()),
+    }
+}
 
-#[cfg(unix)]
-fn ready_of_interest() -> Ready {
-    Ready::readable() | UnixReady::hup() | UnixReady::error()
+// Prints a status message, keeping it out of stdout when that is meant for a machine to read
+fn banner(format: OutputFormat, message: &str) {
+    match format {
+        // a closed stdout is noticed by the output instead
+        OutputFormat::Text => {
+            let _ = writeln!(io::stdout(), "{}", message);
+        }
+        OutputFormat::JsonLines => eprintln!("{}", message),
+    }
 }
 
-#[cfg(windows)]
-fn ready_of_interest() -> Ready {
-    Ready::readable()
+// Exits if an output file could not be created, as the user would be missing what they asked for
+fn created<T>(path: &str, result: io::Result<T>) -> T {
+    match result {
+        Ok(t) => t,
+        Err(e) => {
+            eprintln!("Failed to create \"{}\". Error: {}", path, e);
+            ::std::process::exit(1);
+        }
+    }
 }
 
-#[cfg(unix)]
-fn is_closed(state: Ready) -> bool {
-    state.contains(UnixReady::hup() | UnixReady::error())
+// Prints every serial port the OS knows about along with its USB details, where available
+fn list_ports() {
+    let ports = serial::list_ports().unwrap_or_else(|e| {
+        eprintln!("Failed to list the serial ports. Error: {}", e);
+        ::std::process::exit(1);
+    });
+    let stdout = io::stdout();
+    match write_ports(&mut stdout.lock(), ports) {
+        // e.g. piped into `head`
+        Err(ref e) if e.kind() == io::ErrorKind::BrokenPipe => (),
+        Err(e) => {
+            eprintln!("Failed to list the serial ports. Error: {}", e);
+            ::std::process::exit(1);
+        }
+        Ok(()) => (),
+    }
 }
 
-#[cfg(windows)]
-fn is_closed(_state: Ready) -> bool {
-    false
+fn write_ports<W: Write>(out: &mut W, ports: Vec<SerialPortInfo>) -> io::Result<()> {
+    if ports.is_empty() {
+        writeln!(out, "No serial ports found")?;
+    }
+
+    for port in ports {
+        writeln!(out, "{}", port.port_name)?;
+        match port.port_type {
+            SerialPortType::UsbPort(info) => {
+                writeln!(out, "    Type: USB {:04x}:{:04x}", info.vid, info.pid)?;
+                if let Some(serial_number) = info.serial_number {
+                    writeln!(out, "    Serial number: {}", serial_number)?;
+                }
+                if let Some(manufacturer) = info.manufacturer {
+                    writeln!(out, "    Manufacturer: {}", manufacturer)?;
+                }
+                if let Some(product) = info.product {
+                    writeln!(out, "    Product: {}", product)?;
+                }
+            }
+            SerialPortType::PciPort => writeln!(out, "    Type: PCI")?,
+            SerialPortType::BluetoothPort => writeln!(out, "    Type: Bluetooth")?,
+            SerialPortType::Unknown => writeln!(out, "    Type: Unknown")?,
+        }
+    }
+    Ok(())
 }
 
-#[derive(Debug)]
-enum Error {
-    PollError(StdError),
-    PortClosed,
-    StdError(StdError),
+// Prints decode errors, warnings and periodic statistics to stderr, out of the way of the output
+struct StderrSink;
+
+impl Sink for StderrSink {
+    fn emit(&mut self, event: &Event) {
+        match event.kind {
+            EventKind::DecodeError { ref message, .. } => eprintln!("Decoder error: {}", message),
+            EventKind::Warning { ref message } => eprintln!("{}", message),
+            EventKind::Stats(ref summary) => eprintln!("{}", summary),
+            _ => (),
+        }
+    }
 }
 
-impl From<StdError> for Error {
-    fn from(e: StdError) -> Error {
-        Error::StdError(e)
+// Creates a sink given with --sink
+fn open_sink(
+    spec: &SinkSpec,
+    time_format: &TimeFormat,
+    rotate: &RotatePolicy,
+    warnings: &Warnings,
+) -> Box<dyn Sink> {
+    let formatter = Formatter {
+        format: match spec.kind {
+            SinkKind::JsonLines => OutputFormat::JsonLines,
+            _ => OutputFormat::Text,
+        },
+        time_format: time_format.clone(),
+    };
+    let sink: Box<dyn Sink> = match (spec.kind, &spec.path) {
+        (SinkKind::Csv, Some(path)) => Box::new(created(
+            path,
+            CsvWriter::create(
+                path,
+                formatter.time_format,
+                rotate.clone(),
+                warnings.clone(),
+            ),
+        )),
+        (_, Some(path)) => Box::new(created(
+            path,
+            FileSink::create(path, formatter, rotate.clone(), warnings.clone()),
+        )),
+        // the parser only allows text and JSON on stdout
+        (_, None) => Box::new(StdoutSink::new(formatter, warnings.clone())),
+    };
+    match spec.ports {
+        Some(ref ports) => Box::new(Filtered::ports(sink, ports.clone())),
+        None => sink,
     }
 }
 
-fn handle_packet(p: Packet, itm_port: u8, should_write_newline: &mut bool) -> Result<(), Error> {
-    match p.kind() {
-        &packet::Kind::Instrumentation(ref i) if i.port() == itm_port => {
-            let payload = &i.payload();
-            if let Ok(s) = str::from_utf8(payload) {
-                // remove the new line from the payload (if it exists)
-                // and inject a timestamp and newline in its place
-                for (i, line) in s.split("\n").enumerate() {
-                    if *should_write_newline {
-                        let now = Local::now();
-
-                        // 24 hour format - YYYY-mm-DD HH:MM:SS.FFF
-                        print!("{} ", now.format("%Y-%m-%d %H:%M:%S%.3f"));
-                        *should_write_newline = false;
-                    }
-
-                    print!("{}", line);
-
-                    if i > 0 {
-                        println!();
-                        *should_write_newline = true;
-                    }
-                }
-            } else {
-                println!("Invalid payload: {:?}", payload);
-            }
+fn main() {
+    match run() {
+        Ok(()) => (),
+        Err(e) => {
+            eprintln!("{}", e);
+            ::std::process::exit(1);
         }
-        o => println!("o: {:?}", o),
     }
-    Ok(())
 }
 
-fn main() -> Result<(), Error> {
+fn run() -> Result<(), Error> {
     // get the command line args
     let matches = App::new("Serial Port ITM Decoder")
         .about("Reads ITM encoded bytes off the serial port and prints them to the console")
         .setting(AppSettings::DisableVersion)
+        .setting(AppSettings::SubcommandsNegateReqs)
+        .setting(AppSettings::ArgsNegateSubcommands)
+        .subcommand(
+            SubCommand::with_name("list")
+                .about("Lists the available serial ports along with their USB details"),
+        )
         .arg(
             Arg::with_name("comport")
-                .help("The device path to a serial port (e.g. COM3)")
+                .help("The device path to a serial port (e.g. COM3), a USB VID:PID (e.g. 0483:374b), a USB serial number (e.g. sn:066DFF48) or a URI: serial:///dev/ttyACM0?baud=2000000, file://capture.bin, stdin:// (or -), tcp://localhost:3443, unix:///path/to/socket or fifo:///path/to/fifo")
                 .use_delimiter(false)
-                .required(true),
+                .validator(|s| SourceUri::parse(&s).map(|_| ()))
+                .required_unless("input-file"),
         )
         .arg(
             Arg::with_name("baud")
@@ -111,15 +210,264 @@ fn main() -> Result<(), Error> {
                     Err(e) => Err(e.to_string()),
                 }),
         )
+        .arg(
+            Arg::with_name("input-file")
+                .help("Decode a raw ITM capture file instead of reading from a serial port, or - for stdin")
+                .long("input-file")
+                .short("f")
+                .value_name("path")
+                .takes_value(true)
+                .conflicts_with("comport"),
+        )
+        .arg(
+            Arg::with_name("ports")
+                .help("Decode several stimulus ports at once, prefixing each line with its port number or label (e.g. 0,1=usb,3 or all)")
+                .long("ports")
+                .short("p")
+                .value_name("N[=label]|all")
+                .takes_value(true)
+                .use_delimiter(true)
+                .multiple(true)
+                .number_of_values(1)
+                .validator(|s| Ports::parse(::std::iter::once(s.as_str()), false).map(|_| ())),
+        )
+        .arg(
+            Arg::with_name("flush-timeout")
+                .help("Milliseconds to wait for a newline before printing a partial line (0 waits forever)")
+                .long("flush-timeout")
+                .value_name("ms")
+                .default_value("1000")
+                .validator(|s| match s.parse::<u64>() {
+                    Ok(_) => Ok(()),
+                    Err(e) => Err(e.to_string()),
+                }),
+        )
+        .arg(
+            Arg::with_name("lossy")
+                .help("Replace invalid UTF-8 with U+FFFD instead of printing the raw bytes")
+                .long("lossy"),
+        )
+        .arg(
+            Arg::with_name("reconnect")
+                .help("Wait for the port to reappear and carry on when it is disconnected")
+                .long("reconnect")
+                .short("r"),
+        )
+        .arg(
+            Arg::with_name("data-bits")
+                .help("The number of data bits per character")
+                .long("data-bits")
+                .value_name("5|6|7|8")
+                .default_value("8")
+                .validator(|s| serial::parse_data_bits(&s).map(|_| ())),
+        )
+        .arg(
+            Arg::with_name("parity")
+                .help("The parity checking mode")
+                .long("parity")
+                .value_name("none|odd|even")
+                .default_value("none")
+                .validator(|s| serial::parse_parity(&s).map(|_| ())),
+        )
+        .arg(
+            Arg::with_name("stop-bits")
+                .help("The number of stop bits")
+                .long("stop-bits")
+                .value_name("1|2")
+                .default_value("1")
+                .validator(|s| serial::parse_stop_bits(&s).map(|_| ())),
+        )
+        .arg(
+            Arg::with_name("flow-control")
+                .help("The flow control mode")
+                .long("flow-control")
+                .value_name("none|software|hardware")
+                .default_value("none")
+                .validator(|s| serial::parse_flow_control(&s).map(|_| ())),
+        )
+        .arg(
+            Arg::with_name("time-source")
+                .help("Stamp lines with the host clock, the target clock built from ITM timestamp packets, or both")
+                .long("time-source")
+                .value_name("host|target|both")
+                .default_value("host")
+                .validator(|s| TimeSource::parse(&s).map(|_| ())),
+        )
+        .arg(
+            Arg::with_name("timestamp")
+                .help("How to print the host time: none, local, utc, rfc3339 (all with microseconds), elapsed (seconds since start), delta (seconds since the previous line) or a strftime format (e.g. %H:%M:%S%.6f)")
+                .long("timestamp")
+                .value_name("style|format")
+                .validator(|s| HostTime::parse(&s).map(|_| ())),
+        )
+        .arg(
+            Arg::with_name("tpiu-freq")
+                .help("The ITM local timestamp clock frequency in Hz (the processor clock divided by the timestamp prescaler), used to convert target timestamps into seconds")
+                .long("tpiu-freq")
+                .value_name("Hz")
+                .validator(|s| match s.parse::<u64>() {
+                    Ok(0) => Err("The frequency must be greater than 0".to_string()),
+                    Ok(_) => Ok(()),
+                    Err(e) => Err(e.to_string()),
+                }),
+        )
+        .arg(
+            Arg::with_name("record")
+                .help("Record every byte read from the port into a capture file")
+                .long("record")
+                .value_name("file")
+                .takes_value(true)
+                .conflicts_with("input-file"),
+        )
+        .arg(
+            Arg::with_name("record-format")
+                .help("Record the bytes as received (raw) or with the host receive time of each chunk (framed)")
+                .long("record-format")
+                .value_name("raw|framed")
+                .default_value("raw")
+                .validator(|s| RecordFormat::parse(&s).map(|_| ())),
+        )
+        .arg(
+            Arg::with_name("replay-speed")
+                .help("How fast to replay a framed capture relative to its original timing (e.g. 2 or 0.5), or max for as fast as possible")
+                .long("replay-speed")
+                .value_name("factor|max")
+                .default_value("1")
+                .validator(|s| parse_replay_speed(&s).map(|_| ())),
+        )
+        .arg(
+            Arg::with_name("port")
+                .help("Decode the values written to a stimulus port as little endian numbers instead of text (e.g. 5=u32, 6=i16 or 7=f32)")
+                .long("port")
+                .value_name("N=u8|i8|u16|i16|u32|i32|f32")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+                .validator(|s| Ports::parse_type(&s).map(|_| ())),
+        )
+        .arg(
+            Arg::with_name("csv")
+                .help("Write the values decoded from numeric ports to a CSV file instead of the console")
+                .long("csv")
+                .value_name("file")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("output-dir")
+                .help("Write the output of each stimulus port to a log file of its own in this directory")
+                .long("output-dir")
+                .value_name("dir")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("output-template")
+                .help("The name of each log file, where {n} is the port number, {label} its label and {date} today's date")
+                .long("output-template")
+                .value_name("template")
+                .default_value(port_log::DEFAULT_TEMPLATE)
+                .validator(|s| port_log::check_template(&s)),
+        )
+        .arg(
+            Arg::with_name("echo")
+                .help("Print the port output written to log files to the console too")
+                .long("echo"),
+        )
+        .arg(
+            Arg::with_name("sink")
+                .help("Send events to another output as kind:path[@ports], where kind is text, jsonl or csv, a path of - is the console (replacing the default console output) and ports limits it to some stimulus ports (e.g. text:-@0 or jsonl:trace.jsonl)")
+                .long("sink")
+                .value_name("kind:path[@ports]")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+                .validator(|s| SinkSpec::parse(&s).map(|_| ())),
+        )
+        .group(ArgGroup::with_name("file-output").args(&["output-dir", "csv", "sink"]).multiple(true))
+        .arg(
+            Arg::with_name("rotate-size")
+                .help("Rotate log and CSV files before they grow beyond this size (e.g. 100M)")
+                .long("rotate-size")
+                .value_name("bytes[K|M|G]")
+                .requires("file-output")
+                .validator(|s| rotate::parse_size(&s).map(|_| ())),
+        )
+        .arg(
+            Arg::with_name("rotate-daily")
+                .help("Rotate log and CSV files at midnight")
+                .long("rotate-daily")
+                .requires("file-output"),
+        )
+        .arg(
+            Arg::with_name("rotate-gzip")
+                .help("Compress rotated files with gzip")
+                .long("rotate-gzip")
+                .requires("file-output"),
+        )
+        .arg(
+            Arg::with_name("rotate-keep")
+                .help("The number of rotated files to keep per log or CSV file, deleting the oldest")
+                .long("rotate-keep")
+                .value_name("count")
+                .requires("file-output")
+                .validator(|s| match s.parse::<usize>() {
+                    Ok(_) => Ok(()),
+                    Err(e) => Err(e.to_string()),
+                }),
+        )
+        .arg(
+            Arg::with_name("error-markers")
+                .help("Show decode errors inline in the output as well as on stderr")
+                .long("error-markers"),
+        )
+        .arg(
+            Arg::with_name("stats-interval")
+                .help("Print the overflow, decode error and throughput statistics to stderr every so many seconds, as well as on exit")
+                .long("stats-interval")
+                .value_name("seconds")
+                .validator(|s| match s.parse::<u64>() {
+                    Ok(0) => Err("The interval must be greater than 0".to_string()),
+                    Ok(_) => Ok(()),
+                    Err(e) => Err(e.to_string()),
+                }),
+        )
+        .arg(
+            Arg::with_name("tpiu-formatter")
+                .help("Strip the 16 byte frames a TPIU in formatted (continuous) mode wraps the trace in, decoding the ITM trace source")
+                .long("tpiu-formatter"),
+        )
+        .arg(
+            Arg::with_name("tpiu-itm-id")
+                .help("The trace ID of the ITM in the TPIU frames [default: 1]")
+                .long("tpiu-itm-id")
+                .value_name("id")
+                .requires("tpiu-formatter")
+                .validator(|s| tpiu::parse_id(&s).map(|_| ())),
+        )
+        .arg(
+            Arg::with_name("tpiu-save")
+                .help("Save the raw output of another trace source in the TPIU frames to a file (e.g. 2=etm.bin for an ETM)")
+                .long("tpiu-save")
+                .value_name("ID=file")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+                .requires("tpiu-formatter")
+                .validator(|s| tpiu::parse_save(&s).map(|_| ())),
+        )
+        .arg(
+            Arg::with_name("format")
+                .help("Print human readable text or one JSON object per line (jsonl)")
+                .long("format")
+                .value_name("text|jsonl")
+                .default_value("text")
+                .validator(|s| OutputFormat::parse(&s).map(|_| ())),
+        )
         .get_matches();
 
-    let com_port_name = matches.value_of("comport").unwrap();
-
-    let baud_rate = matches
-        .value_of("baud")
-        .unwrap()
-        .parse::<u32>()
-        .expect("Invalid baud rate");
+    if matches.subcommand_matches("list").is_some() {
+        list_ports();
+        return Ok(());
+    }
 
     let itm_port = matches
         .value_of("itmport")
@@ -127,59 +475,356 @@ fn main() -> Result<(), Error> {
         .parse::<u8>()
         .expect("Arg validator should ensure this parses");
 
-    // Set up mio & mio serialport
+    let lossy = matches.is_present("lossy");
+    let mut ports = match matches.values_of("ports") {
+        Some(entries) => {
+            Ports::parse(entries, lossy).expect("Arg validator should ensure this parses")
+        }
+        None => Ports::single(itm_port, lossy),
+    };
+    for entry in matches.values_of("port").into_iter().flatten() {
+        let (port, ty) = Ports::parse_type(entry).expect("Arg validator should ensure this parses");
+        ports.set_type(port, ty);
+    }
+    let flush_timeout = match matches
+        .value_of("flush-timeout")
+        .unwrap() // We supplied a default value
+        .parse::<u64>()
+        .expect("Arg validator should ensure this parses")
+    {
+        0 => None,
+        ms => Some(Duration::from_millis(ms)),
+    };
+
+    let time_format = TimeFormat {
+        source: TimeSource::parse(matches.value_of("time-source").unwrap()) // We supplied a default value
+            .expect("Arg validator should ensure this parses"),
+        host: matches
+            .value_of("timestamp")
+            .map(|s| HostTime::parse(s).expect("Arg validator should ensure this parses"))
+            .unwrap_or_default(),
+        tpiu_freq: matches.value_of("tpiu-freq").map(|s| {
+            s.parse::<u64>()
+                .expect("Arg validator should ensure this parses")
+        }),
+    };
+    let format = OutputFormat::parse(matches.value_of("format").unwrap()) // We supplied a default value
+        .expect("Arg validator should ensure this parses");
+    let rotate = RotatePolicy {
+        max_size: matches
+            .value_of("rotate-size")
+            .map(|s| rotate::parse_size(s).expect("Arg validator should ensure this parses")),
+        daily: matches.is_present("rotate-daily"),
+        gzip: matches.is_present("rotate-gzip"),
+        keep: matches.value_of("rotate-keep").map(|s| {
+            s.parse::<usize>()
+                .expect("Arg validator should ensure this parses")
+        }),
+    };
+    let formatter = Formatter {
+        format,
+        time_format,
+    };
+    let specs = matches
+        .values_of("sink")
+        .into_iter()
+        .flatten()
+        .map(|s| SinkSpec::parse(s).expect("Arg validator should ensure this parses"))
+        .collect::<Vec<_>>();
+    let csv = matches.is_present("csv");
+    let logs = matches.is_present("output-dir");
+    let echo = matches.is_present("echo");
+
+    // problems writing the outputs are reported on stderr rather than stopping decoding
+    let warnings = Warnings::new();
+    let mut sinks = Sinks::new();
+    // the console is left to the sinks given on the command line, if any
+    if specs.is_empty() {
+        // samples go to the CSV file and port output to the log files instead, unless echoed
+        let console = Box::new(StdoutSink::new(formatter.clone(), warnings.clone()));
+        sinks.push(Box::new(Filtered::new(console, move |event| {
+            match event.kind {
+                EventKind::Sample { .. } if csv => false,
+                ref kind => !logs || echo || kind.port().is_none(),
+            }
+        })));
+    }
+    if let Some(path) = matches.value_of("csv") {
+        let writer = CsvWriter::create(
+            path,
+            formatter.time_format.clone(),
+            rotate.clone(),
+            warnings.clone(),
+        );
+        sinks.push(Box::new(created(path, writer)));
+    }
+    if let Some(dir) = matches.value_of("output-dir") {
+        let template = matches.value_of("output-template").unwrap(); // We supplied a default value
+        let port_logs = created(
+            dir,
+            PortLogs::create(
+                dir,
+                template,
+                formatter.clone(),
+                rotate.clone(),
+                NUM_STIMULUS_PORTS,
+                warnings.clone(),
+            ),
+        );
+        sinks.push(Box::new(Filtered::new(Box::new(port_logs), move |event| {
+            !(csv && matches!(event.kind, EventKind::Sample { .. }))
+        })));
+    }
+    for spec in specs.iter() {
+        sinks.push(open_sink(spec, &formatter.time_format, &rotate, &warnings));
+    }
+
+    // keep status messages out of stdout if JSON is printed there
+    let stdout_format = if specs.is_empty() {
+        format
+    } else if specs
+        .iter()
+        .any(|spec| spec.kind == SinkKind::JsonLines && spec.path.is_none())
+    {
+        OutputFormat::JsonLines
+    } else {
+        OutputFormat::Text
+    };
+
+    // decode errors only show up in the output as markers if asked for
+    let markers = matches.is_present("error-markers");
+    let output = Filtered::new(Box::new(sinks), move |event| match event.kind {
+        EventKind::DecodeError { .. } => markers,
+        EventKind::Stats(_) | EventKind::Warning { .. } => false,
+        _ => true,
+    });
+    let mut sinks = Sinks::new();
+    sinks.push(Box::new(StderrSink));
+    sinks.push(Box::new(output));
+
+    let mut session = Session::new(ports, Box::new(sinks));
+    session.flush_timeout = flush_timeout;
+    session.warnings = warnings.clone();
+    session.stats = Stats::new(
+        NUM_STIMULUS_PORTS,
+        matches.value_of("stats-interval").map(|s| {
+            Duration::from_secs(
+                s.parse::<u64>()
+                    .expect("Arg validator should ensure this parses"),
+            )
+        }),
+    );
+
+    // Set up mio
     let poll = Poll::new().unwrap();
-    let mut events = Events::with_capacity(1024);
-    let mut mio_settings = mio_serial::SerialPortSettings::default();
-
-    // Set the baud rate and timout
-    mio_settings.timeout = Duration::from_millis(10);
-    mio_settings.baud_rate = baud_rate;
-
-    // open the serial port and begin reading ITM packets
-    match mio_serial::Serial::from_path(&com_port_name, &mio_settings) {
-        Ok(port) => {
-            poll.register(&port, SERIAL_TOKEN, ready_of_interest(), PollOpt::edge())
-                .map_err(|e| Error::PollError(e))?;
-
-            println!(
-                "Receiving ITM data (port {}) on {} at {:?} baud:",
-                &itm_port, &com_port_name, &baud_rate
-            );
-            let mut decoder = Decoder::new(port, false);
-            let mut should_write_newline = true;
-            loop {
-                poll.poll(&mut events, None)
-                    .map_err(|e| Error::PollError(e))?;
-
-                if events.is_empty() {
-                    // Read times out every couple of seconds - no need to log this
-                    continue;
-                }
+    let (interrupt, handle) = Interrupt::new();
+    let installed = ctrlc::set_handler(move || {
+        // a second Ctrl-C gives up on stopping cleanly, e.g. if a write is stuck
+        if handle.interrupt() {
+            ::std::process::exit(130);
+        }
+    });
+    if let Err(e) = installed {
+        eprintln!("Failed to install the Ctrl-C handler. Error: {}", e);
+        ::std::process::exit(1);
+    }
+    poll.register(
+        &interrupt,
+        stream::INTERRUPT_TOKEN,
+        Ready::readable(),
+        PollOpt::edge(),
+    )
+    .map_err(Error::Poll)?;
+
+    let result = decode_input(&matches, stdout_format, &poll, &mut session, &interrupt);
+    eprintln!("{}", session.stats.summary());
+    // closing the outputs waits for rotated files to be compressed, which may fail too
+    drop(session);
+    for message in warnings.take() {
+        eprintln!("{}", message);
+    }
+    match result {
+        // Ctrl-C is the usual way to stop reading from a port, and piping into e.g. `head` to stop
+        // once enough has been seen
+        Err(Error::Interrupted) | Err(Error::OutputClosed) => Ok(()),
+        result => result,
+    }
+}
+
+// Opens the input file, TCP address or serial port given on the command line and decodes it
+fn decode_input(
+    matches: &ArgMatches,
+    format: OutputFormat,
+    poll: &Poll,
+    session: &mut Session,
+    interrupt: &Interrupt,
+) -> Result<(), Error> {
+    let port_desc = match matches.values_of("ports") {
+        Some(entries) => entries.collect::<Vec<_>>().join(","),
+        None => matches.value_of("itmport").unwrap().to_string(), // We supplied a default value
+    };
+
+    let mut uri = match matches.value_of("input-file") {
+        Some("-") => SourceUri::Stdin,
+        Some(path) => SourceUri::File {
+            path: path.into(),
+            speed: None,
+        },
+        None => SourceUri::parse(matches.value_of("comport").unwrap())
+            .expect("Arg validator should ensure this parses"),
+    };
+    if let SourceUri::File { ref mut speed, .. } = uri {
+        // We supplied a default value and the arg validator ensures this parses
+        *speed = parse_replay_speed(matches.value_of("replay-speed").unwrap()).unwrap();
+    }
+
+    let tpiu = if matches.is_present("tpiu-formatter") {
+        let saves = matches
+            .values_of("tpiu-save")
+            .into_iter()
+            .flatten()
+            .map(|s| tpiu::parse_save(s).expect("Arg validator should ensure this parses"))
+            .collect::<Vec<_>>();
+        let files = if saves.is_empty() {
+            None
+        } else {
+            let files = SourceFiles::create(&saves, session.warnings.clone()).unwrap_or_else(|e| {
+                eprintln!("Failed to create the trace source files. Error: {}", e);
+                ::std::process::exit(1);
+            });
+            Some(Rc::new(RefCell::new(files)))
+        };
+        Some(TpiuOptions {
+            itm_id: matches
+                .value_of("tpiu-itm-id")
+                .map(|s| tpiu::parse_id(s).expect("Arg validator should ensure this parses"))
+                .unwrap_or(tpiu::DEFAULT_ITM_ID),
+            files,
+            bytes_read: Rc::default(),
+        })
+    } else {
+        None
+    };
+    // the statistics are of the bytes received rather than those left once deformatted
+    if let Some(ref tpiu) = tpiu {
+        session.stats.wire_bytes = Some(tpiu.bytes_read.clone());
+    }
+
+    if let SourceUri::File { .. } = uri {
+        if matches.is_present("record") {
+            eprintln!("A capture file cannot be recorded again");
+            ::std::process::exit(1);
+        }
+    }
 
-                for event in events.iter() {
-                    match event.token() {
-                        SERIAL_TOKEN => {
-                            let ready = event.readiness();
-                            if is_closed(ready) {
-                                Err(Error::PortClosed)?;
-                            }
-
-                            if ready.is_readable() {
-                                // With edge triggered events, we must perform reading until we receive a WouldBlock.
-                                // See https://docs.rs/mio/0.6/mio/struct.Poll.html for details.
-                                while let Ok(p) = decoder.read_packet() {
-                                    handle_packet(p, itm_port, &mut should_write_newline)?;
-                                }
-                            }
-                        }
-                        t => unreachable!("Unexpected token: {:?}", t),
-                    }
+    // shared by every connection so that a reconnect carries on with the same capture file
+    let recorder = match matches.value_of("record") {
+        Some(path) => {
+            // We supplied a default value and the arg validator ensures this parses
+            let format = RecordFormat::parse(matches.value_of("record-format").unwrap()).unwrap();
+            match Recorder::create(path, format, session.warnings.clone()) {
+                Ok(recorder) => Some(Rc::new(RefCell::new(recorder))),
+                Err(e) => {
+                    eprintln!("Failed to create \"{}\". Error: {}", path, e);
+                    ::std::process::exit(1);
                 }
             }
         }
+        None => None,
+    };
+    let reconnect = matches.is_present("reconnect");
+
+    let baud_rate = matches
+        .value_of("baud")
+        .unwrap()
+        .parse::<u32>()
+        .expect("Invalid baud rate");
+
+    // Set the baud rate, line settings and timout
+    // We supplied default values and the arg validators ensure these parse
+    let mut mio_settings = mio_serial::SerialPortSettings {
+        timeout: Duration::from_millis(10),
+        baud_rate,
+        data_bits: serial::parse_data_bits(matches.value_of("data-bits").unwrap()).unwrap(),
+        parity: serial::parse_parity(matches.value_of("parity").unwrap()).unwrap(),
+        stop_bits: serial::parse_stop_bits(matches.value_of("stop-bits").unwrap()).unwrap(),
+        flow_control: serial::parse_flow_control(matches.value_of("flow-control").unwrap())
+            .unwrap(),
+    };
+    if let SourceUri::Serial { ref params, .. } = uri {
+        mio_settings = params.apply(&mio_settings);
+    }
+
+    // a file redirected to stdin cannot be polled, but then it doesn't need to be
+    if let SourceUri::Stdin = uri {
+        let pollable = source::stdin_is_pollable().unwrap_or_else(|e| {
+            eprintln!("Failed to open \"stdin\". Error: {}", e);
+            ::std::process::exit(1);
+        });
+        if !pollable {
+            eprintln!("Receiving ITM data (port {}) from stdin:", &port_desc);
+            let input = Tee::new(io::stdin(), recorder.clone());
+            return match tpiu {
+                Some(ref tpiu) => replay_file(tpiu.deformatter(input), session, interrupt),
+                None => replay_file(input, session, interrupt),
+            };
+        }
+    }
+
+    // open the input and begin reading ITM packets
+    let name = uri.to_string();
+    let open = || {
+        // the capture gets the bytes as received, TPIU frames and all
+        let input = Tee::new(uri.open(&mio_settings)?, recorder.clone());
+        Ok(match tpiu {
+            Some(ref tpiu) => Box::new(tpiu.deformatter(input)) as Box<dyn Source>,
+            None => Box::new(input),
+        })
+    };
+    match open() {
+        Ok(input) => {
+            let message = match uri {
+                SourceUri::Serial { .. } => format!(
+                    "Receiving ITM data (port {}) on {} at {}:",
+                    &port_desc,
+                    &name,
+                    serial::describe(&mio_settings)
+                ),
+                _ => format!("Receiving ITM data (port {}) from {}:", &port_desc, &name),
+            };
+            if let SourceUri::Stdin = uri {
+                // keep stdout for the decoded output when used as a filter in a pipeline
+                eprintln!("{}", message);
+                return match stream_packets(poll, input, session) {
+                    // the other end of the pipe is done
+                    Err(Error::PortClosed) => Ok(()),
+                    result => result,
+                };
+            }
+            if let SourceUri::File { .. } = uri {
+                return match stream_packets(poll, input, session) {
+                    // the end of the capture
+                    Err(Error::PortClosed) => Ok(()),
+                    result => result,
+                };
+            }
+            banner(format, &message);
+            match stream_with_reconnect(poll, input, open, &name, session, reconnect, interrupt) {
+                // the writer of a FIFO is done, and waiting for the next one wasn't asked for
+                Err(Error::PortClosed) if matches!(uri, SourceUri::Fifo(_)) => Ok(()),
+                result => result,
+            }
+        }
         Err(e) => {
-            eprintln!("Failed to open \"{}\". Error: {}", com_port_name, e);
+            match uri {
+                SourceUri::Tcp(_) | SourceUri::Unix(_) => {
+                    eprintln!("Failed to connect to \"{}\". Error: {}", name, e)
+                }
+                _ => eprintln!("Failed to open \"{}\". Error: {}", name, e),
+            }
+            if let SourceUri::Serial { .. } = uri {
+                eprintln!("Run \"serialitm list\" to see the available serial ports");
+            }
             ::std::process::exit(1);
         }
     }
@@ -0,0 +1,165 @@
+use std::fmt;
+
+/// The type of the little endian values written to a numeric stimulus port
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum NumericType {
+    U8,
+    I8,
+    U16,
+    I16,
+    U32,
+    I32,
+    F32,
+}
+
+impl NumericType {
+    pub fn parse(s: &str) -> Result<NumericType, String> {
+        match s {
+            "u8" => Ok(NumericType::U8),
+            "i8" => Ok(NumericType::I8),
+            "u16" => Ok(NumericType::U16),
+            "i16" => Ok(NumericType::I16),
+            "u32" => Ok(NumericType::U32),
+            "i32" => Ok(NumericType::I32),
+            "f32" => Ok(NumericType::F32),
+            _ => Err(format!(
+                "Invalid data type: {} (expected u8, i8, u16, i16, u32, i32 or f32)",
+                s
+            )),
+        }
+    }
+
+    /// The number of bytes in one value
+    pub fn size(self) -> usize {
+        match self {
+            NumericType::U8 | NumericType::I8 => 1,
+            NumericType::U16 | NumericType::I16 => 2,
+            NumericType::U32 | NumericType::I32 | NumericType::F32 => 4,
+        }
+    }
+
+    fn decode(self, bytes: &[u8]) -> Sample {
+        let mut buf = [0; 4];
+        buf[..bytes.len()].copy_from_slice(bytes);
+        match self {
+            NumericType::U8 => Sample::Unsigned(u32::from(buf[0])),
+            NumericType::I8 => Sample::Signed(i32::from(buf[0] as i8)),
+            NumericType::U16 => Sample::Unsigned(u32::from(u16::from_le_bytes([buf[0], buf[1]]))),
+            NumericType::I16 => Sample::Signed(i32::from(i16::from_le_bytes([buf[0], buf[1]]))),
+            NumericType::U32 => Sample::Unsigned(u32::from_le_bytes(buf)),
+            NumericType::I32 => Sample::Signed(i32::from_le_bytes(buf)),
+            NumericType::F32 => Sample::Float(f32::from_le_bytes(buf)),
+        }
+    }
+}
+
+/// A single value decoded from a numeric stimulus port
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Sample {
+    Unsigned(u32),
+    Signed(i32),
+    Float(f32),
+}
+
+impl fmt::Display for Sample {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Sample::Unsigned(v) => write!(f, "{}", v),
+            Sample::Signed(v) => write!(f, "{}", v),
+            Sample::Float(v) => write!(f, "{}", v),
+        }
+    }
+}
+
+/// Splits the payloads written to one stimulus port into values, carrying a partial value over
+/// to the next payload so that e.g. a u32 sent as four 8 bit writes still decodes
+pub struct NumericDecoder {
+    ty: NumericType,
+    pending: Vec<u8>,
+}
+
+impl NumericDecoder {
+    pub fn new(ty: NumericType) -> NumericDecoder {
+        NumericDecoder {
+            ty,
+            pending: Vec::new(),
+        }
+    }
+
+    /// Drops a partial value, e.g. after the input was reconnected
+    pub fn reset(&mut self) {
+        self.pending.clear();
+    }
+
+    pub fn push(&mut self, payload: &[u8]) -> Vec<Sample> {
+        self.pending.extend_from_slice(payload);
+        let size = self.ty.size();
+        let whole = self.pending.len() - self.pending.len() % size;
+        let samples = self.pending[..whole]
+            .chunks(size)
+            .map(|bytes| self.ty.decode(bytes))
+            .collect();
+        self.pending.drain(..whole);
+        samples
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn value_split_across_payloads() {
+        let mut decoder = NumericDecoder::new(NumericType::U32);
+        assert!(decoder.push(&[0x78]).is_empty());
+        assert!(decoder.push(&[0x56]).is_empty());
+        assert!(decoder.push(&[0x34]).is_empty());
+        assert_eq!(decoder.push(&[0x12]), vec![Sample::Unsigned(0x1234_5678)]);
+    }
+
+    #[test]
+    fn several_values_in_one_payload() {
+        let mut decoder = NumericDecoder::new(NumericType::U16);
+        assert_eq!(
+            decoder.push(&[1, 0, 2, 0]),
+            vec![Sample::Unsigned(1), Sample::Unsigned(2)]
+        );
+        // the odd byte waits for the rest of its value
+        assert_eq!(decoder.push(&[3, 0, 4]), vec![Sample::Unsigned(3)]);
+        decoder.reset();
+        assert_eq!(decoder.push(&[5, 0]), vec![Sample::Unsigned(5)]);
+    }
+
+    #[test]
+    fn signed_values_are_sign_extended() {
+        assert_eq!(NumericType::I8.decode(&[0xff]), Sample::Signed(-1));
+        assert_eq!(NumericType::I8.decode(&[0x80]), Sample::Signed(-128));
+        assert_eq!(NumericType::U8.decode(&[0xff]), Sample::Unsigned(255));
+        assert_eq!(NumericType::I16.decode(&[0xfe, 0xff]), Sample::Signed(-2));
+        assert_eq!(
+            NumericType::I16.decode(&[0x00, 0x80]),
+            Sample::Signed(-32768)
+        );
+        assert_eq!(
+            NumericType::U16.decode(&[0xfe, 0xff]),
+            Sample::Unsigned(0xfffe)
+        );
+        assert_eq!(
+            NumericType::I32.decode(&[0xff, 0xff, 0xff, 0xff]),
+            Sample::Signed(-1)
+        );
+    }
+
+    #[test]
+    fn floats() {
+        assert_eq!(
+            NumericType::F32.decode(&1.5f32.to_le_bytes()),
+            Sample::Float(1.5)
+        );
+        assert_eq!(
+            NumericType::F32.decode(&(-0.25f32).to_le_bytes()),
+            Sample::Float(-0.25)
+        );
+        assert_eq!(Sample::Float(1.5).to_string(), "1.5");
+    }
+}
@@ -0,0 +1,318 @@
+//! Formats decoded events as human readable text or JSON Lines and writes them to stdout or a
+//! file.
+
+use crate::{
+    decoder::exception_name,
+    event::{access_name, function_name, Event, EventKind},
+    numeric::Sample,
+    rotate::{RotatePolicy, RotatingFile},
+    sink::Sink,
+    timestamp::TimeFormat,
+    warning::{Stoppable, Warnings},
+};
+use chrono::SecondsFormat;
+use serde_json::{json, Map, Value};
+use std::{
+    io::{self, Write},
+    path::Path,
+};
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum OutputFormat {
+    /// Human readable lines, as printed by earlier versions
+    Text,
+    /// One JSON object per event
+    JsonLines,
+}
+
+impl OutputFormat {
+    pub fn parse(s: &str) -> Result<OutputFormat, String> {
+        match s {
+            "text" => Ok(OutputFormat::Text),
+            "jsonl" => Ok(OutputFormat::JsonLines),
+            _ => Err(format!("Invalid format: {} (expected text or jsonl)", s)),
+        }
+    }
+}
+
+/// Turns events into lines of text or JSON, stamped as configured
+#[derive(Clone)]
+pub struct Formatter {
+    pub format: OutputFormat,
+    pub time_format: TimeFormat,
+}
+
+/// Prints events to stdout. When the reader goes away (e.g. piped into `head`) the sink is
+/// closed, which ends the session rather than being reported as a failure.
+pub struct StdoutSink {
+    formatter: Formatter,
+    out: Stoppable<io::Stdout>,
+    closed: bool,
+}
+
+impl StdoutSink {
+    pub fn new(formatter: Formatter, warnings: Warnings) -> StdoutSink {
+        StdoutSink {
+            formatter,
+            out: Stoppable::new(io::stdout(), "stdout".to_string(), warnings),
+            closed: false,
+        }
+    }
+}
+
+impl Sink for StdoutSink {
+    fn emit(&mut self, event: &Event) {
+        if self.closed {
+            return;
+        }
+        if let Some(line) = self.formatter.format(event) {
+            let closed = &mut self.closed;
+            self.out
+                .write(|out| match writeln!(out.lock(), "{}", line) {
+                    Err(ref e) if e.kind() == io::ErrorKind::BrokenPipe => {
+                        *closed = true;
+                        Ok(())
+                    }
+                    result => result,
+                });
+        }
+    }
+
+    fn is_closed(&self) -> bool {
+        self.closed
+    }
+}
+
+/// Writes events to a file, which is rotated as the policy says
+pub struct FileSink {
+    formatter: Formatter,
+    out: Stoppable<RotatingFile>,
+}
+
+impl FileSink {
+    /// Appends to the file at `path` if it exists already
+    pub fn create<P: AsRef<Path>>(
+        path: P,
+        formatter: Formatter,
+        policy: RotatePolicy,
+        warnings: Warnings,
+    ) -> io::Result<FileSink> {
+        let path = path.as_ref();
+        let out = RotatingFile::create(path, None, policy, warnings.clone())?;
+        Ok(FileSink {
+            formatter,
+            out: Stoppable::new(out, path.display().to_string(), warnings),
+        })
+    }
+}
+
+impl Sink for FileSink {
+    fn emit(&mut self, event: &Event) {
+        if let Some(line) = self.formatter.format(event) {
+            self.out.write(|out| out.write_line(&line));
+        }
+    }
+
+    fn flush(&mut self) {
+        self.out.write(RotatingFile::flush);
+    }
+}
+
+impl Formatter {
+    /// Formats an event as a single line, or `None` if it is not printed in this format
+    pub fn format(&self, event: &Event) -> Option<String> {
+        match self.format {
+            OutputFormat::Text => self.to_text(event),
+            OutputFormat::JsonLines => Some(self.to_json(event).to_string()),
+        }
+    }
+
+    fn to_text(&self, event: &Event) -> Option<String> {
+        let text = match event.kind {
+            EventKind::Line {
+                ref label,
+                ref text,
+                ..
+            } => match label {
+                Some(label) => format!("[{}] {}", label, text),
+                None => text.clone(),
+            },
+            EventKind::Sample {
+                ref label, value, ..
+            } => match label {
+                Some(label) => format!("[{}] {}", label, value),
+                None => value.to_string(),
+            },
+            EventKind::InvalidPayload { ref bytes, .. } => {
+                return Some(format!("Invalid payload: {:?}", bytes))
+            }
+            EventKind::Exception { number, function } => format!(
+                "Exception {} ({}) {}",
+                exception_name(number),
+                number,
+                function_name(function)
+            ),
+            EventKind::PcSample { pc: Some(pc) } => format!("PC sample 0x{:08x}", pc),
+            EventKind::PcSample { pc: None } => "PC sample: sleeping".to_string(),
+            EventKind::DataTracePc { comparator, pc } => {
+                format!("Data trace comparator {} PC 0x{:08x}", comparator, pc)
+            }
+            EventKind::DataTraceAddress {
+                comparator,
+                address,
+            } => format!(
+                "Data trace comparator {} address 0x{:04x}",
+                comparator, address
+            ),
+            EventKind::DataTraceValue {
+                comparator,
+                access,
+                value,
+                size,
+            } => format!(
+                "Data trace comparator {} {} 0x{:0width$x}",
+                comparator,
+                access_name(access),
+                value,
+                width = size * 2
+            ),
+            EventKind::EventCounter(counter) => {
+                format!("Event counter wrap: {}", counter.names().join(" "))
+            }
+            EventKind::Overflow => return Some("o: Overflow".to_string()),
+            EventKind::DecodeError { ref message, .. } => {
+                format!("--- decode error: {} ---", message)
+            }
+            // the target time is already printed with every line
+            EventKind::Timestamp { .. } => return None,
+            EventKind::Connection {
+                ref name,
+                connected,
+            } => format!(
+                "--- {} {} ---",
+                name,
+                if connected {
+                    "reconnected"
+                } else {
+                    "disconnected"
+                }
+            ),
+            EventKind::Warning { ref message } => format!("--- {} ---", message),
+            // statistics are a summary of the output rather than a part of it
+            EventKind::Stats(_) => return None,
+            EventKind::Other(ref p) => return Some(format!("o: {:?}", p)),
+        };
+
+        // only formatted for stamped events so that a delta is measured from the previous one
+        let time = self.time_format.format(&event.host_time, event.target_time);
+        if time.is_empty() {
+            Some(text)
+        } else {
+            Some(format!("{} {}", time, text))
+        }
+    }
+
+    fn to_json(&self, event: &Event) -> Value {
+        let mut object = Map::new();
+        object.insert(
+            "host_time".to_string(),
+            json!(event
+                .host_time
+                .to_rfc3339_opts(SecondsFormat::Micros, false)),
+        );
+        object.insert("target_time".to_string(), json!(event.target_time));
+        if let Some(micros) = self.time_format.target_micros(event.target_time) {
+            object.insert("target_us".to_string(), json!(micros));
+        }
+
+        let fields = match event.kind {
+            EventKind::Line {
+                port,
+                ref label,
+                ref text,
+            } => json!({ "kind": "line", "port": port, "label": label, "text": text }),
+            EventKind::Sample {
+                port,
+                ref label,
+                value,
+            } => {
+                let value = match value {
+                    Sample::Unsigned(v) => json!(v),
+                    Sample::Signed(v) => json!(v),
+                    Sample::Float(v) => json!(v),
+                };
+                json!({ "kind": "sample", "port": port, "label": label, "value": value })
+            }
+            EventKind::InvalidPayload { port, ref bytes } => {
+                json!({ "kind": "invalid_payload", "port": port, "bytes": bytes })
+            }
+            EventKind::Exception { number, function } => json!({
+                "kind": "exception",
+                "number": number,
+                "name": exception_name(number),
+                "function": function_name(function),
+            }),
+            EventKind::PcSample { pc } => {
+                json!({ "kind": "pc_sample", "pc": pc, "sleeping": pc.is_none() })
+            }
+            EventKind::DataTracePc { comparator, pc } => {
+                json!({ "kind": "data_trace_pc", "comparator": comparator, "pc": pc })
+            }
+            EventKind::DataTraceAddress {
+                comparator,
+                address,
+            } => json!({
+                "kind": "data_trace_address",
+                "comparator": comparator,
+                "address": address,
+            }),
+            EventKind::DataTraceValue {
+                comparator,
+                access,
+                value,
+                size,
+            } => json!({
+                "kind": "data_trace_value",
+                "comparator": comparator,
+                "access": access_name(access),
+                "value": value,
+                "size": size,
+            }),
+            EventKind::EventCounter(counter) => {
+                json!({ "kind": "event_counter", "counters": counter.names() })
+            }
+            EventKind::Overflow => json!({ "kind": "overflow" }),
+            EventKind::DecodeError {
+                ref message,
+                skipped,
+            } => json!({ "kind": "decode_error", "message": message, "skipped": skipped }),
+            EventKind::Timestamp { global } => json!({ "kind": "timestamp", "global": global }),
+            EventKind::Connection {
+                ref name,
+                connected,
+            } => json!({ "kind": "connection", "name": name, "connected": connected }),
+            EventKind::Stats(ref summary) => json!({
+                "kind": "stats",
+                "elapsed_s": summary.elapsed.as_secs_f64(),
+                "input_bytes": summary.input_bytes,
+                "packets": summary.packets,
+                "overflows": summary.overflows,
+                "decode_errors": summary.decode_errors,
+                "skipped_bytes": summary.skipped_bytes,
+                "invalid_payloads": summary.invalid_payloads,
+                "ports": summary.ports.iter().map(|port| json!({
+                    "port": port.port,
+                    "packets": port.packets,
+                    "bytes": port.bytes,
+                })).collect::<Vec<_>>(),
+            }),
+            EventKind::Warning { ref message } => json!({ "kind": "warning", "message": message }),
+            EventKind::Other(ref p) => json!({ "kind": "other", "packet": format!("{:?}", p) }),
+        };
+
+        if let Value::Object(fields) = fields {
+            object.extend(fields);
+        }
+        Value::Object(object)
+    }
+}
@@ -0,0 +1,114 @@
+//! Writes the lines decoded from each stimulus port to a log file of its own, named after a
+//! template such as `port{n}_{date}.log`.
+
+use crate::{
+    event::Event,
+    output::Formatter,
+    rotate::{RotatePolicy, RotatingFile},
+    sink::Sink,
+    warning::{Stoppable, Warnings},
+};
+use std::{fs, io, path::PathBuf};
+
+pub const DEFAULT_TEMPLATE: &str = "port{n}_{date}.log";
+
+/// Checks that a filename template names a file and not a directory
+pub fn check_template(template: &str) -> Result<(), String> {
+    if template.is_empty() || template.contains(['/', '\\']) {
+        return Err(format!(
+            "Invalid filename template: {} (expected a file name such as {})",
+            template, DEFAULT_TEMPLATE
+        ));
+    }
+    Ok(())
+}
+
+struct LogFile {
+    // kept per file so that e.g. a delta is measured from the previous line in the same file
+    formatter: Formatter,
+    out: Stoppable<RotatingFile>,
+}
+
+/// Writes the events of each stimulus port to its log file and ignores events without a port
+pub struct PortLogs {
+    formatter: Formatter,
+    dir: PathBuf,
+    template: String,
+    policy: RotatePolicy,
+    warnings: Warnings,
+    // Opened when a port first writes something so that unused ports don't leave empty files
+    files: Vec<Option<LogFile>>,
+}
+
+impl PortLogs {
+    /// Creates `dir` if it does not exist yet
+    pub fn create<P: Into<PathBuf>>(
+        dir: P,
+        template: &str,
+        formatter: Formatter,
+        policy: RotatePolicy,
+        ports: usize,
+        warnings: Warnings,
+    ) -> io::Result<PortLogs> {
+        let dir = dir.into();
+        fs::create_dir_all(&dir)?;
+        Ok(PortLogs {
+            formatter,
+            dir,
+            template: template.to_string(),
+            policy,
+            warnings,
+            files: (0..ports).map(|_| None).collect(),
+        })
+    }
+
+    /// The name of the log file for `port`, expanding `{n}` and `{label}` (but not `{date}`,
+    /// which changes as the file is rotated)
+    fn name(&self, port: u8, label: Option<&str>) -> String {
+        // a label is free text so keep it from escaping the output directory
+        let label = label.unwrap_or_default().replace(['/', '\\'], "_");
+        self.template
+            .replace("{n}", &port.to_string())
+            .replace("{label}", &label)
+    }
+
+    /// The log file for `port`, opened if this is the first time the port has written anything
+    fn file(&mut self, port: u8, label: Option<&str>) -> Option<&mut LogFile> {
+        if self.files[port as usize].is_none() {
+            let name = self.name(port, label);
+            let opened = RotatingFile::open(
+                &self.dir,
+                &name,
+                None,
+                self.policy.clone(),
+                self.warnings.clone(),
+            );
+            let path = self.dir.join(name).display().to_string();
+            self.files[port as usize] = Some(LogFile {
+                formatter: self.formatter.clone(),
+                out: Stoppable::open(opened, path, self.warnings.clone()),
+            });
+        }
+        self.files[port as usize].as_mut()
+    }
+}
+
+impl Sink for PortLogs {
+    fn emit(&mut self, event: &Event) {
+        let port = match event.kind.port() {
+            Some(port) => port,
+            None => return,
+        };
+        if let Some(file) = self.file(port, event.kind.label()) {
+            if let Some(line) = file.formatter.format(event) {
+                file.out.write(|out| out.write_line(&line));
+            }
+        }
+    }
+
+    fn flush(&mut self) {
+        for file in self.files.iter_mut().flatten() {
+            file.out.write(RotatingFile::flush);
+        }
+    }
+}
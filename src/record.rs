@@ -0,0 +1,145 @@
+//! Tees the raw bytes read from the input into a capture file so a session can be replayed later.
+//!
+//! A raw capture is just the bytes as received. A framed capture starts with `FRAMED_MAGIC`
+//! followed by one frame per chunk read from the input:
+//!
+//! ``` text
+//! u64 little endian  host receive time in microseconds since the UNIX epoch
+//! u32 little endian  length of the chunk
+//! [u8; length]       the chunk
+//! ```
+
+use crate::warning::{Stoppable, Warnings};
+use mio::{Evented, Poll, PollOpt, Ready, Token};
+use std::{
+    cell::RefCell,
+    fs::File,
+    io::{self, BufWriter, Read, Write},
+    path::Path,
+    rc::Rc,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+/// Identifies a framed capture file
+pub const FRAMED_MAGIC: &[u8; 8] = b"SITMCAP1";
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum RecordFormat {
+    Raw,
+    Framed,
+}
+
+impl RecordFormat {
+    pub fn parse(s: &str) -> Result<RecordFormat, String> {
+        match s {
+            "raw" => Ok(RecordFormat::Raw),
+            "framed" => Ok(RecordFormat::Framed),
+            _ => Err(format!(
+                "Invalid record format: {} (expected raw or framed)",
+                s
+            )),
+        }
+    }
+}
+
+pub struct Recorder {
+    out: Stoppable<BufWriter<File>>,
+    format: RecordFormat,
+}
+
+impl Recorder {
+    pub fn create<P: AsRef<Path>>(
+        path: P,
+        format: RecordFormat,
+        warnings: Warnings,
+    ) -> io::Result<Recorder> {
+        let path = path.as_ref();
+        let mut out = BufWriter::new(File::create(path)?);
+        if format == RecordFormat::Framed {
+            out.write_all(FRAMED_MAGIC)?;
+        }
+
+        Ok(Recorder {
+            out: Stoppable::new(out, path.display().to_string(), warnings),
+            format,
+        })
+    }
+
+    fn write_chunk(&mut self, chunk: &[u8]) {
+        let format = self.format;
+        self.out
+            .write(|out| Recorder::write_frame(out, format, chunk));
+    }
+
+    fn write_frame(
+        out: &mut BufWriter<File>,
+        format: RecordFormat,
+        chunk: &[u8],
+    ) -> io::Result<()> {
+        if format == RecordFormat::Framed {
+            let micros = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_micros() as u64;
+            out.write_all(&micros.to_le_bytes())?;
+            out.write_all(&(chunk.len() as u32).to_le_bytes())?;
+        }
+        out.write_all(chunk)
+    }
+
+    pub fn flush(&mut self) {
+        self.out.write(BufWriter::flush);
+    }
+}
+
+/// Passes reads through to `inner`, copying every byte read into the recorder (if any)
+pub struct Tee<R> {
+    inner: R,
+    recorder: Option<Rc<RefCell<Recorder>>>,
+}
+
+impl<R> Tee<R> {
+    pub fn new(inner: R, recorder: Option<Rc<RefCell<Recorder>>>) -> Tee<R> {
+        Tee { inner, recorder }
+    }
+}
+
+impl<R: Read> Read for Tee<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let result = self.inner.read(buf);
+        if let Some(ref recorder) = self.recorder {
+            match result {
+                Ok(n) if n > 0 => recorder.borrow_mut().write_chunk(&buf[..n]),
+                // the input has gone quiet so it is a good time to get the capture onto disk
+                Ok(_) | Err(_) => recorder.borrow_mut().flush(),
+            }
+        }
+        result
+    }
+}
+
+impl<R: Evented> Evented for Tee<R> {
+    fn register(
+        &self,
+        poll: &Poll,
+        token: Token,
+        interest: Ready,
+        opts: PollOpt,
+    ) -> io::Result<()> {
+        self.inner.register(poll, token, interest, opts)
+    }
+
+    fn reregister(
+        &self,
+        poll: &Poll,
+        token: Token,
+        interest: Ready,
+        opts: PollOpt,
+    ) -> io::Result<()> {
+        self.inner.reregister(poll, token, interest, opts)
+    }
+
+    fn deregister(&self, poll: &Poll) -> io::Result<()> {
+        self.inner.deregister(poll)
+    }
+}
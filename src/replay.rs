@@ -0,0 +1,203 @@
+//! Replays a framed capture (see `record`) with its original inter-chunk delays so that it can
+//! drive the same poll loop as a live port. Readers that cannot be polled, such as stdin on
+//! Windows, are read on such a thread too.
+
+use crate::record::FRAMED_MAGIC;
+use mio::{Evented, Poll, PollOpt, Ready, Registration, SetReadiness, Token};
+use std::{
+    fs::File,
+    io::{self, BufReader, Read, Seek, SeekFrom},
+    sync::mpsc::{self, Receiver, TryRecvError},
+    thread,
+    time::{Duration, Instant},
+};
+
+/// Reads the framed capture header, returning false if the file is a raw capture
+pub fn is_framed(file: &mut File) -> io::Result<bool> {
+    let mut magic = [0; FRAMED_MAGIC.len()];
+    let framed = match file.read_exact(&mut magic) {
+        Ok(()) => &magic == FRAMED_MAGIC,
+        Err(ref e) if e.kind() == io::ErrorKind::UnexpectedEof => false,
+        Err(e) => return Err(e),
+    };
+
+    if !framed {
+        file.seek(SeekFrom::Start(0))?;
+    }
+    Ok(framed)
+}
+
+/// Reads the next frame, returning `None` at the end of the capture
+fn read_frame<R: Read>(reader: &mut R) -> io::Result<Option<(u64, Vec<u8>)>> {
+    let mut micros = [0; 8];
+    match reader.read_exact(&mut micros) {
+        Ok(()) => (),
+        Err(ref e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e),
+    }
+
+    let mut len = [0; 4];
+    reader.read_exact(&mut len)?;
+    let mut chunk = vec![0; u32::from_le_bytes(len) as usize];
+    reader.read_exact(&mut chunk)?;
+    Ok(Some((u64::from_le_bytes(micros), chunk)))
+}
+
+/// Re-emits the chunks of a framed capture, or of any other reader, from a background thread
+pub struct Replay {
+    registration: Registration,
+    set_readiness: SetReadiness,
+    rx: Receiver<io::Result<Vec<u8>>>,
+    chunk: Vec<u8>,
+    pos: usize,
+}
+
+impl Replay {
+    /// `file` must be positioned just after the header (see `is_framed`). The original delays are
+    /// divided by `speed`, or skipped altogether if it is `None`.
+    pub fn start(file: File, speed: Option<f64>) -> Replay {
+        Replay::spawn(move |send| {
+            let mut reader = BufReader::new(file);
+            let started = Instant::now();
+            let mut first = None;
+            loop {
+                let (micros, chunk) = match read_frame(&mut reader) {
+                    Ok(Some(frame)) => frame,
+                    Ok(None) => return,
+                    Err(e) => {
+                        send(Err(e));
+                        return;
+                    }
+                };
+
+                // sleep until the chunk is due rather than for the gap since the previous
+                // one so that delays don't accumulate
+                if let Some(speed) = speed {
+                    let first = *first.get_or_insert(micros);
+                    let due = Duration::from_micros(micros.saturating_sub(first)).div_f64(speed);
+                    if let Some(wait) = due.checked_sub(started.elapsed()) {
+                        thread::sleep(wait);
+                    }
+                }
+
+                if !send(Ok(chunk)) {
+                    return;
+                }
+            }
+        })
+    }
+
+    /// Re-emits the chunks read from a reader that blocks, such as stdin on Windows, which
+    /// cannot be polled otherwise
+    pub fn read<R: Read + Send + 'static>(mut reader: R) -> Replay {
+        Replay::spawn(move |send| {
+            let mut buf = [0; 1024];
+            loop {
+                match reader.read(&mut buf) {
+                    Ok(0) => return,
+                    Ok(n) => {
+                        if !send(Ok(buf[..n].to_vec())) {
+                            return;
+                        }
+                    }
+                    Err(ref e) if e.kind() == io::ErrorKind::Interrupted => (),
+                    Err(e) => {
+                        send(Err(e));
+                        return;
+                    }
+                }
+            }
+        })
+    }
+
+    /// Runs `produce` on a background thread, which hands over each chunk with `send` until it
+    /// returns false as the `Replay` has been dropped. Returning marks the end of the input, and
+    /// an error sent last is returned by `read`.
+    fn spawn<F>(produce: F) -> Replay
+    where
+        F: FnOnce(&dyn Fn(io::Result<Vec<u8>>) -> bool) + Send + 'static,
+    {
+        let (registration, set_readiness) = Registration::new2();
+        let (tx, rx) = mpsc::channel();
+        let thread_readiness = set_readiness.clone();
+
+        thread::spawn(move || {
+            produce(&|chunk| {
+                if tx.send(chunk).is_err() {
+                    return false;
+                }
+                let _ = thread_readiness.set_readiness(Ready::readable());
+                true
+            });
+
+            // wake the reader so it sees the end of the input
+            drop(tx);
+            let _ = thread_readiness.set_readiness(Ready::readable());
+        });
+
+        Replay {
+            registration,
+            set_readiness,
+            rx,
+            chunk: Vec::new(),
+            pos: 0,
+        }
+    }
+}
+
+impl Read for Replay {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.pos == self.chunk.len() {
+            let chunk = match self.rx.try_recv() {
+                // clear the readiness before checking again so that a chunk sent in between
+                // still produces a new event
+                Err(TryRecvError::Empty) => {
+                    self.set_readiness.set_readiness(Ready::empty())?;
+                    self.rx.try_recv()
+                }
+                result => result,
+            };
+
+            match chunk {
+                Ok(Ok(chunk)) => {
+                    self.chunk = chunk;
+                    self.pos = 0;
+                }
+                Ok(Err(e)) => return Err(e),
+                Err(TryRecvError::Empty) => return Err(io::ErrorKind::WouldBlock.into()),
+                Err(TryRecvError::Disconnected) => return Ok(0),
+            }
+        }
+
+        let n = buf.len().min(self.chunk.len() - self.pos);
+        buf[..n].copy_from_slice(&self.chunk[self.pos..self.pos + n]);
+        self.pos += n;
+        Ok(n)
+    }
+}
+
+impl Evented for Replay {
+    fn register(
+        &self,
+        poll: &Poll,
+        token: Token,
+        interest: Ready,
+        opts: PollOpt,
+    ) -> io::Result<()> {
+        self.registration.register(poll, token, interest, opts)
+    }
+
+    fn reregister(
+        &self,
+        poll: &Poll,
+        token: Token,
+        interest: Ready,
+        opts: PollOpt,
+    ) -> io::Result<()> {
+        self.registration.reregister(poll, token, interest, opts)
+    }
+
+    fn deregister(&self, poll: &Poll) -> io::Result<()> {
+        poll.deregister(&self.registration)
+    }
+}
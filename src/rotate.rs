@@ -0,0 +1,338 @@
+//! A log file that is rotated when it grows too large or the day changes. Rotated files are
+//! optionally compressed with gzip and only the most recent ones are kept.
+
+use crate::warning::Warnings;
+use chrono::{Local, NaiveDate};
+use flate2::{write::GzEncoder, Compression};
+use std::{
+    cmp::Reverse,
+    fs::{self, File, OpenOptions},
+    io::{self, BufWriter, Write},
+    path::{Path, PathBuf},
+    thread::{self, JoinHandle},
+};
+
+#[derive(Clone, Default)]
+pub struct RotatePolicy {
+    /// Rotate before a file grows beyond this many bytes
+    pub max_size: Option<u64>,
+    /// Rotate when the local date changes
+    pub daily: bool,
+    pub gzip: bool,
+    /// The number of rotated files to keep, or `None` to keep them all
+    pub keep: Option<usize>,
+}
+
+/// Parses a size in bytes with an optional K, M or G suffix (e.g. 100M)
+pub fn parse_size(s: &str) -> Result<u64, String> {
+    let (digits, multiplier) = match s.chars().last().map(|c| c.to_ascii_uppercase()) {
+        Some('K') => (&s[..s.len() - 1], 1 << 10),
+        Some('M') => (&s[..s.len() - 1], 1 << 20),
+        Some('G') => (&s[..s.len() - 1], 1 << 30),
+        _ => (s, 1),
+    };
+
+    match digits.parse::<u64>() {
+        Ok(0) => Err("The size must be greater than 0".to_string()),
+        Ok(n) => n
+            .checked_mul(multiplier)
+            .ok_or_else(|| format!("Invalid size: {} (too large)", s)),
+        Err(_) => Err(format!(
+            "Invalid size: {} (expected bytes with an optional K, M or G suffix, e.g. 100M)",
+            s
+        )),
+    }
+}
+
+pub struct RotatingFile {
+    dir: PathBuf,
+    /// The file name, in which `{date}` is replaced with the date the file was opened
+    template: String,
+    /// Written at the start of every new file
+    header: Option<String>,
+    policy: RotatePolicy,
+    path: PathBuf,
+    out: BufWriter<File>,
+    size: u64,
+    opened: NaiveDate,
+    warnings: Warnings,
+    /// Compresses and prunes the files rotated so far, joined on drop so that exiting doesn't
+    /// leave a truncated `.gz` behind
+    worker: Option<JoinHandle<()>>,
+}
+
+impl RotatingFile {
+    /// Opens the file named after `template` in `dir`, appending to it if it exists already
+    pub fn open<P: Into<PathBuf>>(
+        dir: P,
+        template: &str,
+        header: Option<&str>,
+        policy: RotatePolicy,
+        warnings: Warnings,
+    ) -> io::Result<RotatingFile> {
+        let dir = dir.into();
+        let today = Local::now().date_naive();
+        let path = dir.join(expand(template, today));
+        let (out, size) = RotatingFile::open_file(&path, header)?;
+        Ok(RotatingFile {
+            dir,
+            template: template.to_string(),
+            header: header.map(str::to_string),
+            policy,
+            path,
+            out,
+            size,
+            opened: today,
+            warnings,
+            worker: None,
+        })
+    }
+
+    /// Opens the file at `path`, which is rotated alongside it in the same directory
+    pub fn create(
+        path: &Path,
+        header: Option<&str>,
+        policy: RotatePolicy,
+        warnings: Warnings,
+    ) -> io::Result<RotatingFile> {
+        let dir = match path.parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => dir,
+            _ => Path::new("."),
+        };
+        let name = path.file_name().unwrap_or_default().to_string_lossy();
+        RotatingFile::open(dir, &name, header, policy, warnings)
+    }
+
+    fn open_file(path: &Path, header: Option<&str>) -> io::Result<(BufWriter<File>, u64)> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        let mut size = file.metadata()?.len();
+        let mut out = BufWriter::new(file);
+        if let (0, Some(header)) = (size, header) {
+            writeln!(out, "{}", header)?;
+            size = header.len() as u64 + 1;
+        }
+        Ok((out, size))
+    }
+
+    pub fn write_line(&mut self, line: &str) -> io::Result<()> {
+        let len = line.len() as u64 + 1;
+        let today = Local::now().date_naive();
+        let too_big = match self.policy.max_size {
+            // a file holding nothing but the header is as small as it gets
+            Some(max) => self.size + len > max && self.size > self.header_len(),
+            None => false,
+        };
+        if too_big || (self.policy.daily && today != self.opened) {
+            self.rotate(today)?;
+        }
+
+        writeln!(self.out, "{}", line)?;
+        self.size += len;
+        Ok(())
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.out.flush()
+    }
+
+    fn header_len(&self) -> u64 {
+        self.header.as_ref().map_or(0, |h| h.len() as u64 + 1)
+    }
+
+    fn rotate(&mut self, today: NaiveDate) -> io::Result<()> {
+        self.out.flush()?;
+        let path = self.dir.join(expand(&self.template, today));
+
+        // a template without `{date}` reuses the same name so the old file is moved aside
+        let rotated = if path == self.path {
+            let rotated = unused_path(&self.path);
+            fs::rename(&self.path, &rotated)?;
+            rotated
+        } else {
+            self.path.clone()
+        };
+
+        let (out, size) = RotatingFile::open_file(&path, self.header.as_deref())?;
+        self.out = out;
+        self.size = size;
+        self.path = path;
+        self.opened = today;
+
+        // compressing a large file takes a while so keep it from holding up decoding
+        let policy = self.policy.clone();
+        let template = self.template.clone();
+        let dir = self.dir.clone();
+        let active = self.path.clone();
+        let warnings = self.warnings.clone();
+        // each worker waits for the previous one so that they don't prune the same files
+        let previous = self.worker.take();
+        self.worker = Some(thread::spawn(move || {
+            if let Some(previous) = previous {
+                let _ = previous.join();
+            }
+            if policy.gzip {
+                if let Err(e) = compress(&rotated) {
+                    warnings.push(format!(
+                        "Failed to compress \"{}\". Error: {}",
+                        rotated.display(),
+                        e
+                    ));
+                }
+            }
+            if let Some(keep) = policy.keep {
+                if let Err(e) = prune(&dir, &template, &active, keep) {
+                    warnings.push(format!("Failed to remove old logs. Error: {}", e));
+                }
+            }
+        }));
+        Ok(())
+    }
+}
+
+impl Drop for RotatingFile {
+    fn drop(&mut self) {
+        if let Some(worker) = self.worker.take() {
+            let _ = worker.join();
+        }
+    }
+}
+
+fn expand(template: &str, date: NaiveDate) -> String {
+    template.replace("{date}", &date.format("%Y-%m-%d").to_string())
+}
+
+// Appends the time of rotation to the file name, e.g. `port0.log.20200101-120000`
+fn unused_path(path: &Path) -> PathBuf {
+    let base = format!(
+        "{}.{}",
+        path.display(),
+        Local::now().format("%Y%m%d-%H%M%S")
+    );
+    let mut rotated = PathBuf::from(&base);
+    let mut n = 1;
+    while rotated.exists() || rotated.with_file_name(gz_name(&rotated)).exists() {
+        rotated = PathBuf::from(format!("{}-{}", base, n));
+        n += 1;
+    }
+    rotated
+}
+
+fn gz_name(path: &Path) -> String {
+    format!(
+        "{}.gz",
+        path.file_name().unwrap_or_default().to_string_lossy()
+    )
+}
+
+fn compress(path: &Path) -> io::Result<()> {
+    let gz_path = path.with_file_name(gz_name(path));
+    let mut input = File::open(path)?;
+    let mut encoder = GzEncoder::new(
+        BufWriter::new(File::create(&gz_path)?),
+        Compression::default(),
+    );
+    io::copy(&mut input, &mut encoder)?;
+    encoder.finish()?.flush()?;
+    fs::remove_file(path)
+}
+
+// Removes all but the `keep` most recently modified files in `dir` rotated from `template`
+fn prune(dir: &Path, template: &str, active: &Path, keep: usize) -> io::Result<()> {
+    let mut files = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let path = entry.path();
+        let name = entry.file_name();
+        if path != active && is_rotated(template, &name.to_string_lossy()) {
+            files.push((entry.metadata()?.modified()?, path));
+        }
+    }
+
+    files.sort_by_key(|&(modified, _)| Reverse(modified));
+    for (_, path) in files.into_iter().skip(keep) {
+        fs::remove_file(path)?;
+    }
+    Ok(())
+}
+
+// Whether `name` is one this module gives a file rotated from `template`: the template with each
+// `{date}` expanded, followed by the suffix added by `unused_path` unless the date tells it apart,
+// and by `.gz` once compressed. Anything else in the directory is left alone.
+fn is_rotated(template: &str, name: &str) -> bool {
+    let name = name.strip_suffix(".gz").unwrap_or(name);
+    let mut parts = template.split("{date}");
+    let mut rest = match name.strip_prefix(parts.next().unwrap_or_default()) {
+        Some(rest) => rest,
+        None => return false,
+    };
+    for part in parts {
+        rest = match rest.get(..10) {
+            Some(date) if is_digits(date, &[4, 7]) => match rest[10..].strip_prefix(part) {
+                Some(rest) => rest,
+                None => return false,
+            },
+            _ => return false,
+        };
+    }
+
+    if rest.is_empty() {
+        return template.contains("{date}");
+    }
+    // `.YYYYmmdd-HHMMSS`, then `-n` if that was taken already
+    match rest.strip_prefix('.').and_then(|rest| rest.get(..15)) {
+        Some(time) if is_digits(time, &[8]) => match &rest[16..] {
+            "" => true,
+            n => match n.strip_prefix('-') {
+                Some(n) => is_digits(n, &[]),
+                None => false,
+            },
+        },
+        _ => false,
+    }
+}
+
+// Whether `s` is all ASCII digits except for a `-` at each of `dashes`
+fn is_digits(s: &str, dashes: &[usize]) -> bool {
+    !s.is_empty()
+        && s.bytes().enumerate().all(|(i, b)| {
+            if dashes.contains(&i) {
+                b == b'-'
+            } else {
+                b.is_ascii_digit()
+            }
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rotated_names() {
+        assert!(is_rotated("trace", "trace.20200101-120000"));
+        assert!(is_rotated("trace", "trace.20200101-120000-2"));
+        assert!(is_rotated("trace", "trace.20200101-120000.gz"));
+        assert!(is_rotated("trace", "trace.20200101-120000-12.gz"));
+        assert!(is_rotated("port{date}.log", "port2020-01-01.log"));
+        assert!(is_rotated("port{date}.log", "port2020-01-01.log.gz"));
+        assert!(is_rotated(
+            "port{date}.log",
+            "port2020-01-01.log.20200101-120000-1.gz"
+        ));
+    }
+
+    #[test]
+    fn other_names_are_not_rotated() {
+        assert!(!is_rotated("trace", "trace"));
+        assert!(!is_rotated("trace", "trace.csv"));
+        assert!(!is_rotated("trace", "trace.gz"));
+        assert!(!is_rotated("trace", "traces.20200101-120000"));
+        assert!(!is_rotated("trace", "trace.20200101-1200"));
+        assert!(!is_rotated("trace", "trace.20200101-120000-"));
+        assert!(!is_rotated("trace", "trace.20200101-120000.bak"));
+        assert!(!is_rotated("port{date}.log", "port.log"));
+        assert!(!is_rotated("port{date}.log", "port2020-01-01.log.old"));
+        assert!(!is_rotated("port{date}.log", "portXXXX-01-01.log"));
+        assert!(!is_rotated("port{date}.log", "port2020-01-01.csv"));
+    }
+}
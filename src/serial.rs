@@ -0,0 +1,155 @@
+use mio_serial::{DataBits, FlowControl, Parity, SerialPortSettings, StopBits};
+use serialport::{SerialPortInfo, SerialPortType, UsbPortInfo};
+use std::{fmt, io};
+
+pub fn parse_data_bits(s: &str) -> Result<DataBits, String> {
+    match s {
+        "5" => Ok(DataBits::Five),
+        "6" => Ok(DataBits::Six),
+        "7" => Ok(DataBits::Seven),
+        "8" => Ok(DataBits::Eight),
+        _ => Err(format!("Invalid data bits: {} (expected 5, 6, 7 or 8)", s)),
+    }
+}
+
+pub fn parse_parity(s: &str) -> Result<Parity, String> {
+    match s.to_lowercase().as_str() {
+        "none" | "n" => Ok(Parity::None),
+        "odd" | "o" => Ok(Parity::Odd),
+        "even" | "e" => Ok(Parity::Even),
+        _ => Err(format!(
+            "Invalid parity: {} (expected none, odd or even)",
+            s
+        )),
+    }
+}
+
+pub fn parse_stop_bits(s: &str) -> Result<StopBits, String> {
+    match s {
+        "1" => Ok(StopBits::One),
+        "2" => Ok(StopBits::Two),
+        _ => Err(format!("Invalid stop bits: {} (expected 1 or 2)", s)),
+    }
+}
+
+pub fn parse_flow_control(s: &str) -> Result<FlowControl, String> {
+    match s.to_lowercase().as_str() {
+        "none" => Ok(FlowControl::None),
+        "software" | "xonxoff" => Ok(FlowControl::Software),
+        "hardware" | "rtscts" => Ok(FlowControl::Hardware),
+        _ => Err(format!(
+            "Invalid flow control: {} (expected none, software or hardware)",
+            s
+        )),
+    }
+}
+
+/// Describes the line settings in the usual shorthand, e.g. `1000000 baud 8N1, no flow control`
+pub fn describe(settings: &SerialPortSettings) -> String {
+    let data_bits = match settings.data_bits {
+        DataBits::Five => 5,
+        DataBits::Six => 6,
+        DataBits::Seven => 7,
+        DataBits::Eight => 8,
+    };
+
+    let parity = match settings.parity {
+        Parity::None => 'N',
+        Parity::Odd => 'O',
+        Parity::Even => 'E',
+    };
+
+    let stop_bits = match settings.stop_bits {
+        StopBits::One => 1,
+        StopBits::Two => 2,
+    };
+
+    let flow_control = match settings.flow_control {
+        FlowControl::None => "no",
+        FlowControl::Software => "software",
+        FlowControl::Hardware => "hardware",
+    };
+
+    format!(
+        "{} baud {}{}{}, {} flow control",
+        settings.baud_rate, data_bits, parity, stop_bits, flow_control
+    )
+}
+
+/// How the user picked the serial port to open
+pub enum PortSelector {
+    /// A device path, e.g. `/dev/ttyACM0` or `COM3`
+    Path(String),
+    /// The first port with this USB vendor and product id, e.g. `0483:374b`
+    UsbId { vid: u16, pid: u16 },
+    /// The port whose USB serial number matches, e.g. `sn:066DFF485457725187092429`
+    SerialNumber(String),
+}
+
+impl PortSelector {
+    pub fn parse(s: &str) -> PortSelector {
+        if let Some(serial_number) = s.strip_prefix("sn:") {
+            return PortSelector::SerialNumber(serial_number.to_string());
+        }
+
+        let mut parts = s.splitn(2, ':');
+        if let (Some(vid), Some(pid)) = (parts.next(), parts.next()) {
+            if vid.len() == 4 && pid.len() == 4 {
+                if let (Ok(vid), Ok(pid)) =
+                    (u16::from_str_radix(vid, 16), u16::from_str_radix(pid, 16))
+                {
+                    return PortSelector::UsbId { vid, pid };
+                }
+            }
+        }
+
+        PortSelector::Path(s.to_string())
+    }
+
+    /// Finds the device path of the selected port. This is done every time the port is opened
+    /// so that a device that comes back under a different path is still found.
+    pub fn resolve(&self) -> io::Result<String> {
+        let usb_matches = |info: &UsbPortInfo| match self {
+            PortSelector::Path(_) => false,
+            PortSelector::UsbId { vid, pid } => info.vid == *vid && info.pid == *pid,
+            PortSelector::SerialNumber(serial_number) => {
+                info.serial_number.as_ref() == Some(serial_number)
+            }
+        };
+
+        if let PortSelector::Path(path) = self {
+            return Ok(path.clone());
+        }
+
+        let mut ports = mio_serial::available_ports()?;
+        ports.sort_by(|a, b| a.port_name.cmp(&b.port_name));
+        ports
+            .into_iter()
+            .find(|port| match port.port_type {
+                SerialPortType::UsbPort(ref info) => usb_matches(info),
+                _ => false,
+            })
+            .map(|port| port.port_name)
+            .ok_or_else(|| {
+                io::Error::new(io::ErrorKind::NotFound, format!("No port matches {}", self))
+            })
+    }
+}
+
+impl fmt::Display for PortSelector {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PortSelector::Path(path) => write!(f, "{}", path),
+            PortSelector::UsbId { vid, pid } => write!(f, "{:04x}:{:04x}", vid, pid),
+            PortSelector::SerialNumber(serial_number) => write!(f, "sn:{}", serial_number),
+        }
+    }
+}
+
+/// Every serial port the OS knows about along with its USB details where available, sorted by
+/// name
+pub fn list_ports() -> io::Result<Vec<SerialPortInfo>> {
+    let mut ports = mio_serial::available_ports()?;
+    ports.sort_by(|a, b| a.port_name.cmp(&b.port_name));
+    Ok(ports)
+}
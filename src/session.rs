@@ -0,0 +1,347 @@
+use crate::{
+    decoder::{DecodeError, Packet},
+    event::{Event, EventKind},
+    line::{Line, LineAssembler, Utf8Decoder},
+    numeric::{NumericDecoder, NumericType},
+    sink::Sink,
+    stats::Stats,
+    timestamp::TargetClock,
+    warning::Warnings,
+};
+use chrono::Local;
+use std::{mem, time::Duration};
+
+/// The number of stimulus ports an ITM unit can have
+pub const NUM_STIMULUS_PORTS: usize = 32;
+
+/// How many packets to hold back waiting for a local timestamp before stamping them with the
+/// previous one, so that a target that never sends timestamps doesn't use up memory
+const MAX_PENDING: usize = 1024;
+
+// Output state kept separately for every stimulus port being decoded
+struct PortState {
+    // Printed at the start of each line when decoding more than one port
+    label: Option<String>,
+    utf8: Utf8Decoder,
+    line: LineAssembler,
+    // Set if the port carries binary values rather than text
+    numeric: Option<NumericDecoder>,
+}
+
+impl PortState {
+    fn new(label: Option<String>, lossy: bool) -> PortState {
+        PortState {
+            label,
+            utf8: Utf8Decoder::new(lossy),
+            line: LineAssembler::default(),
+            numeric: None,
+        }
+    }
+}
+
+/// The stimulus ports to decode, along with their labels and data types
+pub struct Ports {
+    states: Vec<Option<PortState>>,
+}
+
+impl Ports {
+    /// Decodes a single port without labelling its lines
+    pub fn single(port: u8, lossy: bool) -> Ports {
+        let mut ports = Ports::none();
+        ports.states[port as usize] = Some(PortState::new(None, lossy));
+        ports
+    }
+
+    pub fn none() -> Ports {
+        Ports {
+            states: (0..NUM_STIMULUS_PORTS).map(|_| None).collect(),
+        }
+    }
+
+    /// Parses a list of `all`, `N` or `N=label` entries
+    pub fn parse<'a, I: Iterator<Item = &'a str>>(
+        entries: I,
+        lossy: bool,
+    ) -> Result<Ports, String> {
+        let mut ports = Ports::none();
+        for entry in entries {
+            if entry == "all" {
+                for (port, state) in ports.states.iter_mut().enumerate() {
+                    if state.is_none() {
+                        *state = Some(PortState::new(Some(port.to_string()), lossy));
+                    }
+                }
+                continue;
+            }
+
+            let mut parts = entry.splitn(2, '=');
+            let port = parts.next().unwrap_or_default();
+            let port = match port.parse::<u8>() {
+                Ok(port) if (port as usize) < NUM_STIMULUS_PORTS => port,
+                _ => return Err(format!("Invalid stimulus port: {}", port)),
+            };
+            let label = parts
+                .next()
+                .map(str::to_string)
+                .unwrap_or_else(|| port.to_string());
+            ports.states[port as usize] = Some(PortState::new(Some(label), lossy));
+        }
+        Ok(ports)
+    }
+
+    /// Decodes the payloads written to `port` as values of type `ty`, selecting it if need be
+    pub fn set_type(&mut self, port: u8, ty: NumericType) {
+        let state = self.states[port as usize]
+            .get_or_insert_with(|| PortState::new(Some(port.to_string()), false));
+        state.numeric = Some(NumericDecoder::new(ty));
+    }
+
+    /// Parses a `N=type` entry mapping a stimulus port to the type of the values written to it
+    pub fn parse_type(s: &str) -> Result<(u8, NumericType), String> {
+        let mut parts = s.splitn(2, '=');
+        let port = parts.next().unwrap_or_default();
+        let port = match port.parse::<u8>() {
+            Ok(port) if (port as usize) < NUM_STIMULUS_PORTS => port,
+            _ => return Err(format!("Invalid stimulus port: {}", port)),
+        };
+        match parts.next() {
+            Some(ty) => Ok((port, NumericType::parse(ty)?)),
+            None => Err(format!(
+                "Missing data type: {} (expected N=type, e.g. 5=u32)",
+                s
+            )),
+        }
+    }
+
+    fn get_mut(&mut self, port: u8) -> Option<&mut PortState> {
+        self.states.get_mut(port as usize).and_then(|s| s.as_mut())
+    }
+}
+
+/// Everything needed to turn packets into events, kept across reconnects
+pub struct Session {
+    ports: Ports,
+    clock: TargetClock,
+    /// Packets waiting for the local timestamp that follows them
+    pending: Vec<Packet>,
+    sink: Box<dyn Sink>,
+    /// How long to wait for a newline before emitting a partial line, if at all
+    pub flush_timeout: Option<Duration>,
+    pub stats: Stats,
+    /// Handed to the sink as events whenever the input goes quiet or ends
+    pub warnings: Warnings,
+}
+
+impl Session {
+    pub fn new(ports: Ports, sink: Box<dyn Sink>) -> Session {
+        Session {
+            ports,
+            clock: TargetClock::default(),
+            pending: Vec::new(),
+            sink,
+            flush_timeout: None,
+            stats: Stats::new(NUM_STIMULUS_PORTS, None),
+            warnings: Warnings::new(),
+        }
+    }
+
+    /// Emits an event stamped with the current host time
+    pub fn emit(&mut self, target_time: Option<u64>, kind: EventKind) {
+        self.sink.emit(&Event {
+            host_time: Local::now(),
+            target_time,
+            kind,
+        });
+    }
+
+    fn emit_line(sink: &mut dyn Sink, port: u8, state: &PortState, line: Line) {
+        sink.emit(&Event {
+            host_time: line.time,
+            target_time: line.target,
+            kind: EventKind::Line {
+                port,
+                label: state.label.clone(),
+                text: line.text,
+            },
+        });
+    }
+
+    /// Emits partial lines that have waited longer than `timeout` for their newline
+    pub fn flush_expired(&mut self, timeout: Duration) {
+        for (port, state) in self.ports.states.iter_mut().enumerate() {
+            if let Some(state) = state {
+                if let Some(line) = state.line.flush_expired(timeout) {
+                    Session::emit_line(self.sink.as_mut(), port as u8, state, line);
+                }
+            }
+        }
+    }
+
+    /// How long the poll loop can sleep before a partial line needs flushing or the statistics
+    /// need printing
+    pub fn time_left(&self) -> Option<Duration> {
+        let lines = self.flush_timeout.and_then(|timeout| {
+            self.ports
+                .states
+                .iter()
+                .flatten()
+                .filter_map(|state| state.line.time_left(timeout))
+                .min()
+        });
+
+        match (lines, self.stats.time_left()) {
+            (Some(a), Some(b)) => Some(a.min(b)),
+            (a, b) => a.or(b),
+        }
+    }
+
+    /// Reports a decode error as an event; the decoder has already skipped to the next valid
+    /// header
+    pub fn decode_error(&mut self, e: DecodeError) {
+        // keep the error after the packets that came before it
+        self.release_pending();
+        let skipped = match e {
+            DecodeError::Malformed(ref bytes) => bytes.len(),
+            _ => 0,
+        };
+        self.stats.decode_errors += 1;
+        self.stats.skipped_bytes += skipped as u64;
+
+        let target = self.clock.ticks();
+        self.emit(
+            target,
+            EventKind::DecodeError {
+                message: e.to_string(),
+                skipped,
+            },
+        );
+    }
+
+    /// Gets anything buffered by the sink onto disk, stamping the packets still waiting for a
+    /// local timestamp with the latest one as the input has gone quiet
+    pub fn flush(&mut self) {
+        self.release_pending();
+        self.sink.flush();
+        self.report_warnings();
+    }
+
+    fn report_warnings(&mut self) {
+        for message in self.warnings.take() {
+            let target = self.clock.ticks();
+            self.emit(target, EventKind::Warning { message });
+        }
+    }
+
+    /// Whether the output has gone away, see [`Sink::is_closed`]
+    pub fn is_closed(&self) -> bool {
+        self.sink.is_closed()
+    }
+
+    /// Emits any lines that are still waiting for a newline at the end of a stream
+    pub fn finish(&mut self) {
+        self.release_pending();
+        for (port, state) in self.ports.states.iter_mut().enumerate() {
+            if let Some(state) = state {
+                state.utf8.reset();
+                if let Some(ref mut numeric) = state.numeric {
+                    numeric.reset();
+                }
+                if let Some(line) = state.line.flush() {
+                    Session::emit_line(self.sink.as_mut(), port as u8, state, line);
+                }
+            }
+        }
+
+        self.sink.flush();
+        self.report_warnings();
+
+        // the target may well have been reset so its timestamps start again
+        self.clock = TargetClock::default();
+    }
+
+    /// Turns a packet into events. A local timestamp follows the packets it applies to, so
+    /// everything else is held back until the next one arrives or the input goes quiet.
+    pub fn handle_packet(&mut self, p: Packet) {
+        self.stats.packets += 1;
+        if self.clock.update(&p) {
+            if let Packet::LocalTimestamp { .. } = p {
+                self.release_pending();
+            }
+            let global = self.clock.global();
+            self.emit(self.clock.ticks(), EventKind::Timestamp { global });
+            return;
+        }
+
+        self.pending.push(p);
+        if self.pending.len() >= MAX_PENDING {
+            self.release_pending();
+        }
+    }
+
+    /// Turns the packets held back so far into events stamped with the current target time
+    fn release_pending(&mut self) {
+        let target = self.clock.ticks();
+        for p in mem::take(&mut self.pending) {
+            self.handle_stamped(p, target);
+        }
+    }
+
+    fn handle_stamped(&mut self, p: Packet, target: Option<u64>) {
+        match p {
+            Packet::Instrumentation(ref i) => {
+                let port = i.port();
+                self.stats.port_write(port, i.payload().len());
+                let state = match self.ports.get_mut(port) {
+                    Some(state) => state,
+                    None => {
+                        self.emit(target, EventKind::Other(p));
+                        return;
+                    }
+                };
+
+                if let Some(ref mut numeric) = state.numeric {
+                    for value in numeric.push(i.payload()) {
+                        self.sink.emit(&Event {
+                            host_time: Local::now(),
+                            target_time: target,
+                            kind: EventKind::Sample {
+                                port,
+                                label: state.label.clone(),
+                                value,
+                            },
+                        });
+                    }
+                    return;
+                }
+
+                let decoded = state.utf8.push(i.payload());
+                if !decoded.invalid.is_empty() {
+                    self.stats.invalid_payloads += 1;
+                    self.sink.emit(&Event {
+                        host_time: Local::now(),
+                        target_time: target,
+                        kind: EventKind::InvalidPayload {
+                            port,
+                            bytes: decoded.invalid,
+                        },
+                    });
+                }
+
+                // only emit whole lines, each stamped with the time its first byte arrived
+                for line in state.line.push(&decoded.text, target) {
+                    Session::emit_line(self.sink.as_mut(), port, state, line);
+                }
+            }
+            // only there to align the stream
+            Packet::Sync => (),
+            Packet::Overflow => {
+                self.stats.overflows += 1;
+                self.emit(target, EventKind::Overflow)
+            }
+            ref o => match EventKind::from_hardware_packet(o) {
+                Some(kind) => self.emit(target, kind),
+                None => self.emit(target, EventKind::Other(p)),
+            },
+        }
+    }
+}
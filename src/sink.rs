@@ -0,0 +1,173 @@
+//! Where decoded events go. Several sinks can be attached to a session at once, each optionally
+//! limited to some of the stimulus ports, e.g. port 0 to the console and everything to a file.
+
+use crate::{event::Event, session::NUM_STIMULUS_PORTS};
+
+/// Receives the events decoded by a [`Session`](crate::session::Session)
+pub trait Sink {
+    fn emit(&mut self, event: &Event);
+
+    /// Gets anything buffered onto disk. Called when the input goes quiet and when it ends.
+    fn flush(&mut self) {}
+
+    /// Whether the sink can take no more events, e.g. stdout once the reader of the pipe has
+    /// gone, in which case there is no point decoding any further
+    fn is_closed(&self) -> bool {
+        false
+    }
+}
+
+/// Hands every event to each of a number of sinks
+#[derive(Default)]
+pub struct Sinks {
+    sinks: Vec<Box<dyn Sink>>,
+}
+
+impl Sinks {
+    pub fn new() -> Sinks {
+        Sinks::default()
+    }
+
+    pub fn push(&mut self, sink: Box<dyn Sink>) {
+        self.sinks.push(sink);
+    }
+}
+
+impl Sink for Sinks {
+    fn emit(&mut self, event: &Event) {
+        for sink in self.sinks.iter_mut() {
+            sink.emit(event);
+        }
+    }
+
+    fn flush(&mut self) {
+        for sink in self.sinks.iter_mut() {
+            sink.flush();
+        }
+    }
+
+    fn is_closed(&self) -> bool {
+        self.sinks.iter().any(|sink| sink.is_closed())
+    }
+}
+
+/// A set of stimulus ports
+#[derive(Clone)]
+pub struct PortFilter {
+    ports: Vec<bool>,
+}
+
+impl PortFilter {
+    /// Parses a comma separated list of port numbers, or `all`
+    pub fn parse(s: &str) -> Result<PortFilter, String> {
+        let mut ports = vec![false; NUM_STIMULUS_PORTS];
+        for entry in s.split(',') {
+            if entry == "all" {
+                ports = vec![true; NUM_STIMULUS_PORTS];
+                continue;
+            }
+            match entry.parse::<u8>() {
+                Ok(port) if (port as usize) < NUM_STIMULUS_PORTS => ports[port as usize] = true,
+                _ => return Err(format!("Invalid stimulus port: {}", entry)),
+            }
+        }
+        Ok(PortFilter { ports })
+    }
+
+    pub fn contains(&self, port: u8) -> bool {
+        self.ports.get(port as usize).copied().unwrap_or(false)
+    }
+}
+
+/// Passes on only the events a filter accepts
+pub struct Filtered {
+    sink: Box<dyn Sink>,
+    filter: Box<dyn Fn(&Event) -> bool>,
+}
+
+impl Filtered {
+    pub fn new<F: Fn(&Event) -> bool + 'static>(sink: Box<dyn Sink>, filter: F) -> Filtered {
+        Filtered {
+            sink,
+            filter: Box::new(filter),
+        }
+    }
+
+    /// Passes on the events of `ports`, along with the events that don't belong to a port
+    /// (e.g. overflows and exceptions)
+    pub fn ports(sink: Box<dyn Sink>, ports: PortFilter) -> Filtered {
+        Filtered::new(sink, move |event| match event.kind.port() {
+            Some(port) => ports.contains(port),
+            None => true,
+        })
+    }
+}
+
+impl Sink for Filtered {
+    fn emit(&mut self, event: &Event) {
+        if (self.filter)(event) {
+            self.sink.emit(event);
+        }
+    }
+
+    fn flush(&mut self) {
+        self.sink.flush();
+    }
+
+    fn is_closed(&self) -> bool {
+        self.sink.is_closed()
+    }
+}
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum SinkKind {
+    Text,
+    JsonLines,
+    /// Numeric samples only
+    Csv,
+}
+
+/// A sink described on the command line as `kind:path[@ports]`, e.g. `jsonl:trace.jsonl` or
+/// `text:-@0,1`, where a path of `-` means stdout
+pub struct SinkSpec {
+    pub kind: SinkKind,
+    /// `None` for stdout
+    pub path: Option<String>,
+    /// `None` for every port
+    pub ports: Option<PortFilter>,
+}
+
+impl SinkSpec {
+    pub fn parse(s: &str) -> Result<SinkSpec, String> {
+        let invalid = || {
+            format!(
+                "Invalid sink: {} (expected kind:path[@ports], e.g. jsonl:trace.jsonl or text:-@0,1)",
+                s
+            )
+        };
+
+        let mut parts = s.splitn(2, ':');
+        let kind = match parts.next().unwrap_or_default() {
+            "text" => SinkKind::Text,
+            "jsonl" => SinkKind::JsonLines,
+            "csv" => SinkKind::Csv,
+            _ => return Err(invalid()),
+        };
+        let rest = parts.next().ok_or_else(invalid)?;
+
+        let (path, ports) = match rest.rfind('@') {
+            Some(at) => (&rest[..at], Some(PortFilter::parse(&rest[at + 1..])?)),
+            None => (rest, None),
+        };
+        let path = match path {
+            "" => return Err(invalid()),
+            "-" if kind == SinkKind::Csv => {
+                return Err(format!("Invalid sink: {} (CSV needs a file)", s))
+            }
+            "-" => None,
+            path => Some(path.to_string()),
+        };
+
+        Ok(SinkSpec { kind, path, ports })
+    }
+}
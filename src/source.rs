@@ -0,0 +1,458 @@
+//! Where the ITM bytes come from, picked with a URI such as `serial:///dev/ttyACM0?baud=2000000`,
+//! `tcp://127.0.0.1:3443` or `file://capture.bin`.
+
+use crate::{
+    replay::{self, Replay},
+    serial::{self, PortSelector},
+};
+use mio::{net::TcpStream, Evented, Poll, PollOpt, Ready, Token};
+use mio_serial::{DataBits, FlowControl, Parity, SerialPortSettings, StopBits};
+use std::{
+    fmt,
+    fs::File,
+    io::{self, Read},
+    net::TcpStream as StdTcpStream,
+    path::PathBuf,
+};
+
+#[cfg(unix)]
+use mio::unix::EventedFd;
+#[cfg(unix)]
+use nix::{
+    errno::Errno,
+    fcntl::{fcntl, FcntlArg, OFlag},
+};
+#[cfg(unix)]
+use std::{
+    fs::OpenOptions,
+    os::unix::{
+        fs::OpenOptionsExt,
+        io::{AsRawFd, RawFd},
+        net::UnixStream,
+    },
+};
+
+/// Anything ITM bytes can be read from without blocking and registered with a mio `Poll`,
+/// e.g. a serial port, a TCP stream or a replayed capture
+pub trait Source: Read + Evented {}
+
+impl<T: Read + Evented> Source for T {}
+
+impl Evented for Box<dyn Source> {
+    fn register(
+        &self,
+        poll: &Poll,
+        token: Token,
+        interest: Ready,
+        opts: PollOpt,
+    ) -> io::Result<()> {
+        (**self).register(poll, token, interest, opts)
+    }
+
+    fn reregister(
+        &self,
+        poll: &Poll,
+        token: Token,
+        interest: Ready,
+        opts: PollOpt,
+    ) -> io::Result<()> {
+        (**self).reregister(poll, token, interest, opts)
+    }
+
+    fn deregister(&self, poll: &Poll) -> io::Result<()> {
+        (**self).deregister(poll)
+    }
+}
+
+/// Serial line settings given in the query of a `serial://` URI, which take precedence over
+/// those given on the command line
+#[derive(Default)]
+pub struct SerialParams {
+    pub baud_rate: Option<u32>,
+    pub data_bits: Option<DataBits>,
+    pub parity: Option<Parity>,
+    pub stop_bits: Option<StopBits>,
+    pub flow_control: Option<FlowControl>,
+}
+
+impl SerialParams {
+    /// Parses a query such as `baud=2000000&parity=even`
+    fn parse(query: &str) -> Result<SerialParams, String> {
+        let mut params = SerialParams::default();
+        for pair in query.split('&').filter(|pair| !pair.is_empty()) {
+            let mut parts = pair.splitn(2, '=');
+            let key = parts.next().unwrap_or_default();
+            let value = parts.next().unwrap_or_default();
+            match key {
+                "baud" => {
+                    params.baud_rate = Some(
+                        value
+                            .parse::<u32>()
+                            .map_err(|_| format!("Invalid baud rate: {}", value))?,
+                    )
+                }
+                "data_bits" => params.data_bits = Some(serial::parse_data_bits(value)?),
+                "parity" => params.parity = Some(serial::parse_parity(value)?),
+                "stop_bits" => params.stop_bits = Some(serial::parse_stop_bits(value)?),
+                "flow_control" => params.flow_control = Some(serial::parse_flow_control(value)?),
+                _ => {
+                    return Err(format!(
+                        "Invalid serial setting: {} (expected baud, data_bits, parity, stop_bits or flow_control)",
+                        key
+                    ))
+                }
+            }
+        }
+        Ok(params)
+    }
+
+    /// Overrides `settings` with the settings given in the URI
+    pub fn apply(&self, settings: &SerialPortSettings) -> SerialPortSettings {
+        SerialPortSettings {
+            baud_rate: self.baud_rate.unwrap_or(settings.baud_rate),
+            data_bits: self.data_bits.unwrap_or(settings.data_bits),
+            parity: self.parity.unwrap_or(settings.parity),
+            stop_bits: self.stop_bits.unwrap_or(settings.stop_bits),
+            flow_control: self.flow_control.unwrap_or(settings.flow_control),
+            timeout: settings.timeout,
+        }
+    }
+}
+
+/// The input to decode
+pub enum SourceUri {
+    /// `serial://<port>[?baud=N&...]`, or a port given without a scheme as in earlier versions
+    /// (e.g. `COM3`, `0483:374b` or `sn:066DFF48`)
+    Serial {
+        port: PortSelector,
+        params: SerialParams,
+    },
+    /// `file://<path>`, a raw or framed capture. The recorded delays of a framed capture are
+    /// divided by `speed`, or skipped altogether if it is `None`.
+    File { path: PathBuf, speed: Option<f64> },
+    /// `stdin://` or `-`
+    Stdin,
+    /// `tcp://<host>:<port>`, e.g. a debug server such as OpenOCD
+    Tcp(String),
+    /// `unix://<path>`, a Unix domain socket
+    Unix(PathBuf),
+    /// `fifo://<path>`, a named pipe
+    Fifo(PathBuf),
+}
+
+impl SourceUri {
+    pub fn parse(s: &str) -> Result<SourceUri, String> {
+        if s == "-" {
+            return Ok(SourceUri::Stdin);
+        }
+
+        let (scheme, rest) = match s.find("://") {
+            Some(i) => (&s[..i], &s[i + 3..]),
+            None => {
+                return Ok(SourceUri::Serial {
+                    port: PortSelector::parse(s),
+                    params: SerialParams::default(),
+                })
+            }
+        };
+
+        let path = |rest: &str| {
+            if rest.is_empty() {
+                Err(format!("Invalid input: {} (missing path)", s))
+            } else {
+                Ok(PathBuf::from(rest))
+            }
+        };
+
+        match scheme {
+            "serial" => {
+                let mut parts = rest.splitn(2, '?');
+                let port = parts.next().unwrap_or_default();
+                if port.is_empty() {
+                    return Err(format!("Invalid input: {} (missing serial port)", s));
+                }
+                Ok(SourceUri::Serial {
+                    port: PortSelector::parse(port),
+                    params: SerialParams::parse(parts.next().unwrap_or_default())?,
+                })
+            }
+            "file" => Ok(SourceUri::File {
+                path: path(rest)?,
+                speed: Some(1.0),
+            }),
+            "stdin" if rest.is_empty() => Ok(SourceUri::Stdin),
+            "tcp" if !rest.is_empty() => Ok(SourceUri::Tcp(rest.to_string())),
+            "unix" => Ok(SourceUri::Unix(path(rest)?)),
+            "fifo" => Ok(SourceUri::Fifo(path(rest)?)),
+            _ => Err(format!(
+                "Invalid input: {} (expected serial://, file://, stdin://, tcp://, unix:// or fifo://)",
+                s
+            )),
+        }
+    }
+
+    /// Opens the input, resolving a serial port selector again each time so that a device that
+    /// comes back under a different path is still found. A file cannot be polled so it is read
+    /// by a `Replay`, which keeps to the original timing of a framed capture.
+    pub fn open(&self, settings: &SerialPortSettings) -> io::Result<Box<dyn Source>> {
+        match self {
+            SourceUri::Serial { port, .. } => {
+                let path = port.resolve()?;
+                let port = mio_serial::Serial::from_path(&path, settings)?;
+                Ok(Box::new(port))
+            }
+            SourceUri::File { path, speed } => {
+                let mut file = File::open(path)?;
+                if replay::is_framed(&mut file)? {
+                    Ok(Box::new(Replay::start(file, *speed)))
+                } else {
+                    Ok(Box::new(Replay::read(file)))
+                }
+            }
+            SourceUri::Tcp(addr) => {
+                let stream = TcpStream::from_stream(StdTcpStream::connect(addr)?)?;
+                Ok(Box::new(stream))
+            }
+            #[cfg(unix)]
+            SourceUri::Stdin => Ok(Box::new(FdSource::new(io::stdin())?)),
+            #[cfg(unix)]
+            SourceUri::Unix(path) => Ok(Box::new(FdSource::new(UnixStream::connect(path)?)?)),
+            // opened without blocking so that this doesn't wait for a writer to turn up
+            #[cfg(unix)]
+            SourceUri::Fifo(path) => {
+                let fifo = OpenOptions::new()
+                    .read(true)
+                    .custom_flags(OFlag::O_NONBLOCK.bits())
+                    .open(path)?;
+                Ok(Box::new(FdSource::new(fifo)?))
+            }
+            // the console and pipes cannot be polled on Windows so stdin is read on a thread
+            #[cfg(windows)]
+            SourceUri::Stdin => Ok(Box::new(Replay::read(io::stdin()))),
+            #[cfg(windows)]
+            _ => Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                format!("{} is only supported on Unix", self),
+            )),
+        }
+    }
+}
+
+impl fmt::Display for SourceUri {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SourceUri::Serial { port, .. } => write!(f, "{}", port),
+            SourceUri::File { path, .. } => write!(f, "{}", path.display()),
+            SourceUri::Stdin => write!(f, "stdin"),
+            SourceUri::Tcp(addr) => write!(f, "{}", addr),
+            SourceUri::Unix(path) | SourceUri::Fifo(path) => write!(f, "{}", path.display()),
+        }
+    }
+}
+
+/// Whether stdin can be polled, which it cannot be when redirected from a regular file (or a
+/// device such as `/dev/null`). Such a file never blocks so it can be read straight through
+/// with `stream::replay_file` instead.
+#[cfg(unix)]
+pub fn stdin_is_pollable() -> io::Result<bool> {
+    let poll = Poll::new()?;
+    let fd = io::stdin().as_raw_fd();
+    match EventedFd(&fd).register(&poll, Token(0), Ready::readable(), PollOpt::edge()) {
+        Ok(()) => Ok(true),
+        // epoll refuses file descriptors that are always ready
+        Err(ref e) if e.raw_os_error() == Some(Errno::EPERM as i32) => Ok(false),
+        Err(e) => Err(e),
+    }
+}
+
+#[cfg(windows)]
+pub fn stdin_is_pollable() -> io::Result<bool> {
+    Ok(true)
+}
+
+/// A file descriptor read without blocking, e.g. a Unix domain socket, a FIFO or stdin
+#[cfg(unix)]
+pub struct FdSource<T: AsRawFd> {
+    inner: T,
+    // put back when done as a descriptor such as stdin may be shared with other processes
+    flags: OFlag,
+}
+
+#[cfg(unix)]
+impl<T: AsRawFd> FdSource<T> {
+    pub fn new(inner: T) -> io::Result<FdSource<T>> {
+        let fd = inner.as_raw_fd();
+        let flags = OFlag::from_bits_truncate(fcntl(fd, FcntlArg::F_GETFL).map_err(nix_error)?);
+        fcntl(fd, FcntlArg::F_SETFL(flags | OFlag::O_NONBLOCK)).map_err(nix_error)?;
+        Ok(FdSource { inner, flags })
+    }
+
+    fn fd(&self) -> RawFd {
+        self.inner.as_raw_fd()
+    }
+}
+
+#[cfg(unix)]
+fn nix_error(e: nix::Error) -> io::Error {
+    match e.as_errno() {
+        Some(errno) => io::Error::from_raw_os_error(errno as i32),
+        // an invalid path rather than a failed system call
+        None => io::Error::new(io::ErrorKind::InvalidInput, e),
+    }
+}
+
+#[cfg(unix)]
+impl<T: AsRawFd + Read> Read for FdSource<T> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.inner.read(buf)
+    }
+}
+
+#[cfg(unix)]
+impl<T: AsRawFd> Evented for FdSource<T> {
+    fn register(
+        &self,
+        poll: &Poll,
+        token: Token,
+        interest: Ready,
+        opts: PollOpt,
+    ) -> io::Result<()> {
+        EventedFd(&self.fd()).register(poll, token, interest, opts)
+    }
+
+    fn reregister(
+        &self,
+        poll: &Poll,
+        token: Token,
+        interest: Ready,
+        opts: PollOpt,
+    ) -> io::Result<()> {
+        EventedFd(&self.fd()).reregister(poll, token, interest, opts)
+    }
+
+    fn deregister(&self, poll: &Poll) -> io::Result<()> {
+        EventedFd(&self.fd()).deregister(poll)
+    }
+}
+
+#[cfg(unix)]
+impl<T: AsRawFd> Drop for FdSource<T> {
+    fn drop(&mut self) {
+        let _ = fcntl(self.fd(), FcntlArg::F_SETFL(self.flags));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::Path;
+
+    fn parse_error(s: &str) -> String {
+        match SourceUri::parse(s) {
+            Ok(uri) => panic!("\"{}\" parsed as {}", s, uri),
+            Err(e) => e,
+        }
+    }
+
+    #[test]
+    fn serial_with_query() {
+        match SourceUri::parse("serial:///dev/ttyACM0?baud=2000000&parity=even").unwrap() {
+            SourceUri::Serial {
+                port: PortSelector::Path(path),
+                params,
+            } => {
+                assert_eq!(path, "/dev/ttyACM0");
+                assert_eq!(params.baud_rate, Some(2_000_000));
+                assert_eq!(params.parity, Some(Parity::Even));
+                assert_eq!(params.data_bits, None);
+            }
+            _ => panic!("expected a serial port path"),
+        }
+    }
+
+    #[test]
+    fn serial_without_scheme() {
+        assert!(matches!(
+            SourceUri::parse("COM3").unwrap(),
+            SourceUri::Serial { port: PortSelector::Path(ref path), .. } if path == "COM3"
+        ));
+        assert!(matches!(
+            SourceUri::parse("0483:374b").unwrap(),
+            SourceUri::Serial {
+                port: PortSelector::UsbId {
+                    vid: 0x0483,
+                    pid: 0x374b
+                },
+                ..
+            }
+        ));
+        assert!(matches!(
+            SourceUri::parse("sn:066DFF48").unwrap(),
+            SourceUri::Serial { port: PortSelector::SerialNumber(ref sn), .. } if sn == "066DFF48"
+        ));
+    }
+
+    #[test]
+    fn other_schemes() {
+        assert!(matches!(SourceUri::parse("-").unwrap(), SourceUri::Stdin));
+        assert!(matches!(
+            SourceUri::parse("stdin://").unwrap(),
+            SourceUri::Stdin
+        ));
+        assert!(matches!(
+            SourceUri::parse("file://trace.bin").unwrap(),
+            SourceUri::File { ref path, speed: Some(_) } if path == Path::new("trace.bin")
+        ));
+        assert!(matches!(
+            SourceUri::parse("tcp://localhost:3443").unwrap(),
+            SourceUri::Tcp(ref addr) if addr == "localhost:3443"
+        ));
+        assert!(matches!(
+            SourceUri::parse("unix:///tmp/itm.sock").unwrap(),
+            SourceUri::Unix(ref path) if path == Path::new("/tmp/itm.sock")
+        ));
+        assert!(matches!(
+            SourceUri::parse("fifo:///tmp/itm").unwrap(),
+            SourceUri::Fifo(ref path) if path == Path::new("/tmp/itm")
+        ));
+    }
+
+    #[test]
+    fn invalid_uris() {
+        assert!(parse_error("tcp://").contains("expected serial://"));
+        assert!(parse_error("stdin://x").contains("expected serial://"));
+        assert!(parse_error("http://localhost").contains("expected serial://"));
+        assert!(parse_error("file://").contains("missing path"));
+        assert!(parse_error("serial://?baud=115200").contains("missing serial port"));
+    }
+
+    #[test]
+    fn serial_params() {
+        let params = SerialParams::parse("").unwrap();
+        assert_eq!(params.baud_rate, None);
+
+        let params = SerialParams::parse("data_bits=7&stop_bits=2&flow_control=none").unwrap();
+        assert_eq!(params.data_bits, Some(DataBits::Seven));
+        assert_eq!(params.stop_bits, Some(StopBits::Two));
+        assert_eq!(params.flow_control, Some(FlowControl::None));
+
+        let settings = SerialPortSettings::default();
+        let applied = SerialParams::parse("baud=9600").unwrap().apply(&settings);
+        assert_eq!(applied.baud_rate, 9600);
+        assert_eq!(applied.parity, settings.parity);
+    }
+
+    #[test]
+    fn invalid_serial_params() {
+        assert!(SerialParams::parse("speed=9600")
+            .err()
+            .unwrap()
+            .contains("Invalid serial setting: speed"));
+        assert!(SerialParams::parse("baud=fast")
+            .err()
+            .unwrap()
+            .contains("Invalid baud rate: fast"));
+        assert!(SerialParams::parse("parity=sometimes").is_err());
+        assert!(parse_error("serial://COM3?baud=x").contains("Invalid baud rate"));
+    }
+}
@@ -0,0 +1,172 @@
+//! Counts what was received and lost during a session, to tell whether the SWO baud rate keeps
+//! up with the amount of data the firmware is sending.
+
+use std::{
+    cell::Cell,
+    fmt,
+    rc::Rc,
+    time::{Duration, Instant},
+};
+
+#[derive(Clone, Copy, Default)]
+struct PortStats {
+    packets: u64,
+    bytes: u64,
+}
+
+pub struct Stats {
+    started: Instant,
+    /// Bytes read from the input, including packet headers
+    pub input_bytes: u64,
+    /// Counts the bytes read from the input when something between it and the decoder changes
+    /// their number, e.g. the TPIU deformatter
+    pub wire_bytes: Option<Rc<Cell<u64>>>,
+    pub packets: u64,
+    pub overflows: u64,
+    pub decode_errors: u64,
+    pub skipped_bytes: u64,
+    pub invalid_payloads: u64,
+    ports: Vec<PortStats>,
+    /// Print the statistics this often, if set
+    interval: Option<Duration>,
+    next_report: Instant,
+}
+
+impl Stats {
+    pub fn new(ports: usize, interval: Option<Duration>) -> Stats {
+        let started = Instant::now();
+        Stats {
+            started,
+            input_bytes: 0,
+            wire_bytes: None,
+            packets: 0,
+            overflows: 0,
+            decode_errors: 0,
+            skipped_bytes: 0,
+            invalid_payloads: 0,
+            ports: vec![PortStats::default(); ports],
+            interval,
+            next_report: started + interval.unwrap_or_default(),
+        }
+    }
+
+    /// Counts the bytes the decoder has read, or those read from the input before it if they
+    /// are counted separately
+    pub fn count_input(&mut self, decoded: u64) {
+        self.input_bytes += match self.wire_bytes {
+            Some(ref wire) => wire.replace(0),
+            None => decoded,
+        };
+    }
+
+    /// Counts a write to a stimulus port
+    pub fn port_write(&mut self, port: u8, bytes: usize) {
+        if let Some(stats) = self.ports.get_mut(port as usize) {
+            stats.packets += 1;
+            stats.bytes += bytes as u64;
+        }
+    }
+
+    /// How long until the next periodic report is due
+    pub fn time_left(&self) -> Option<Duration> {
+        self.interval
+            .map(|_| self.next_report.saturating_duration_since(Instant::now()))
+    }
+
+    /// Returns the statistics if a periodic report is due
+    pub fn report_if_due(&mut self) -> Option<Summary> {
+        let interval = self.interval?;
+        let now = Instant::now();
+        if now < self.next_report {
+            return None;
+        }
+        // skip reports missed while blocked rather than making them all at once
+        while self.next_report <= now {
+            self.next_report += interval;
+        }
+        Some(self.summary())
+    }
+
+    /// The statistics so far
+    pub fn summary(&self) -> Summary {
+        Summary {
+            elapsed: self.started.elapsed(),
+            input_bytes: self.input_bytes,
+            packets: self.packets,
+            overflows: self.overflows,
+            decode_errors: self.decode_errors,
+            skipped_bytes: self.skipped_bytes,
+            invalid_payloads: self.invalid_payloads,
+            ports: self
+                .ports
+                .iter()
+                .enumerate()
+                .filter(|(_, stats)| stats.packets > 0)
+                .map(|(port, stats)| PortSummary {
+                    port: port as u8,
+                    packets: stats.packets,
+                    bytes: stats.bytes,
+                })
+                .collect(),
+        }
+    }
+}
+
+/// The statistics at some point in a session, displayed as a few lines of text
+#[derive(Clone, Debug, PartialEq)]
+pub struct Summary {
+    pub elapsed: Duration,
+    pub input_bytes: u64,
+    pub packets: u64,
+    pub overflows: u64,
+    pub decode_errors: u64,
+    pub skipped_bytes: u64,
+    pub invalid_payloads: u64,
+    /// Only the ports that have been written to
+    pub ports: Vec<PortSummary>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct PortSummary {
+    pub port: u8,
+    pub packets: u64,
+    pub bytes: u64,
+}
+
+impl Summary {
+    /// The average number of bytes read per second
+    pub fn rate(&self) -> f64 {
+        let elapsed = self.elapsed.as_secs_f64();
+        if elapsed > 0.0 {
+            self.input_bytes as f64 / elapsed
+        } else {
+            0.0
+        }
+    }
+}
+
+impl fmt::Display for Summary {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "--- {:.1}s: {} bytes ({:.0} bytes/s), {} packets ---",
+            self.elapsed.as_secs_f64(),
+            self.input_bytes,
+            self.rate(),
+            self.packets
+        )?;
+        write!(
+            f,
+            "\noverflows: {}, decode errors: {} ({} bytes skipped), invalid payloads: {}",
+            self.overflows, self.decode_errors, self.skipped_bytes, self.invalid_payloads
+        )?;
+        for port in self.ports.iter() {
+            write!(
+                f,
+                "\nport {}: {} packets, {} bytes",
+                port.port, port.packets, port.bytes
+            )?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,228 @@
+use crate::{
+    decoder::{DecodeError, Decoder},
+    event::EventKind,
+    interrupt::Interrupt,
+    session::Session,
+    source::Source,
+    Error,
+};
+#[cfg(unix)]
+use mio::unix::UnixReady;
+use mio::{Events, Poll, PollOpt, Ready, Token};
+use std::{
+    io::{self, Read},
+    thread,
+    time::Duration,
+};
+
+/// The token the input is registered with
+pub const INPUT_TOKEN: Token = Token(0);
+/// The token an `Interrupt` must be registered with to stop `stream_packets`
+pub const INTERRUPT_TOKEN: Token = Token(1);
+
+const MIN_RECONNECT_BACKOFF: Duration = Duration::from_millis(100);
+const MAX_RECONNECT_BACKOFF: Duration = Duration::from_secs(5);
+
+#[cfg(unix)]
+fn ready_of_interest() -> Ready {
+    Ready::readable() | UnixReady::hup() | UnixReady::error()
+}
+
+#[cfg(windows)]
+fn ready_of_interest() -> Ready {
+    Ready::readable()
+}
+
+#[cfg(unix)]
+fn is_closed(state: Ready) -> bool {
+    state.contains(UnixReady::hup() | UnixReady::error())
+}
+
+#[cfg(windows)]
+fn is_closed(_state: Ready) -> bool {
+    false
+}
+
+// A pipe whose writer has gone reports a hang up without being readable
+#[cfg(unix)]
+fn is_hung_up(state: Ready) -> bool {
+    UnixReady::from(state).is_hup()
+}
+
+#[cfg(windows)]
+fn is_hung_up(_state: Ready) -> bool {
+    false
+}
+
+/// Decodes a raw ITM capture (e.g. dumped by OpenOCD or a logic analyzer) until the end of the
+/// file, until `interrupt` is set or until the output is closed
+pub fn replay_file<R: Read>(
+    input: R,
+    session: &mut Session,
+    interrupt: &Interrupt,
+) -> Result<(), Error> {
+    let mut decoder = Decoder::new(input);
+    while !interrupt.is_set() && !session.is_closed() {
+        match decoder.read_packet() {
+            Ok(p) => session.handle_packet(p),
+            Err(DecodeError::Eof) => break,
+            // a capture cut off part way through a packet
+            Err(e @ DecodeError::EofDuringPacket) => {
+                session.decode_error(e);
+                break;
+            }
+            Err(e) => session.decode_error(e),
+        }
+    }
+
+    session.stats.count_input(decoder.take_bytes_read());
+    session.finish();
+    Ok(())
+}
+
+/// Registers the input with mio and decodes ITM packets as they arrive, until the input closes
+/// (`Error::PortClosed`), the output closes (`Error::OutputClosed`) or the `Interrupt` registered
+/// with `INTERRUPT_TOKEN` is set
+pub fn stream_packets<R: Source>(
+    poll: &Poll,
+    input: R,
+    session: &mut Session,
+) -> Result<(), Error> {
+    poll.register(&input, INPUT_TOKEN, ready_of_interest(), PollOpt::edge())
+        .map_err(Error::Poll)?;
+
+    let mut events = Events::with_capacity(1024);
+    let mut decoder = Decoder::new(input);
+    loop {
+        let timeout = session.time_left();
+        poll.poll(&mut events, timeout).map_err(Error::Poll)?;
+
+        if let Some(t) = session.flush_timeout {
+            session.flush_expired(t);
+        }
+        if let Some(summary) = session.stats.report_if_due() {
+            session.emit(None, EventKind::Stats(summary));
+        }
+
+        if events.is_empty() {
+            // Read times out every couple of seconds - no need to log this
+            continue;
+        }
+
+        for event in events.iter() {
+            match event.token() {
+                INPUT_TOKEN => {
+                    let ready = event.readiness();
+                    if is_closed(ready) {
+                        session.finish();
+                        return Err(Error::PortClosed);
+                    }
+
+                    // reading a hung up pipe gets whatever is left, then the end of the input
+                    if ready.is_readable() || is_hung_up(ready) {
+                        // With edge triggered events, we must perform reading until we receive a WouldBlock.
+                        // See https://docs.rs/mio/0.6/mio/struct.Poll.html for details.
+                        loop {
+                            match decoder.read_packet() {
+                                Ok(p) => {
+                                    session.handle_packet(p);
+                                    if session.is_closed() {
+                                        session.stats.count_input(decoder.take_bytes_read());
+                                        session.finish();
+                                        return Err(Error::OutputClosed);
+                                    }
+                                }
+                                // the input has nothing more for now
+                                Err(DecodeError::Io(ref e))
+                                    if e.kind() == io::ErrorKind::WouldBlock =>
+                                {
+                                    session.stats.count_input(decoder.take_bytes_read());
+                                    session.flush();
+                                    break;
+                                }
+                                // the decoder has skipped to the next valid header so keep draining
+                                Err(e @ DecodeError::Malformed(_)) => session.decode_error(e),
+                                // A TCP peer hanging up shows up as the end of the stream and a device
+                                // that has gone away fails to read
+                                Err(e) => {
+                                    match e {
+                                        DecodeError::EofDuringPacket => session.decode_error(e),
+                                        DecodeError::Io(e) => session
+                                            .warnings
+                                            .push(format!("Failed to read input. Error: {}", e)),
+                                        _ => (),
+                                    }
+                                    session.stats.count_input(decoder.take_bytes_read());
+                                    session.finish();
+                                    return Err(Error::PortClosed);
+                                }
+                            }
+                        }
+                    }
+                }
+                // Ctrl-C
+                INTERRUPT_TOKEN => {
+                    session.stats.count_input(decoder.take_bytes_read());
+                    session.finish();
+                    return Err(Error::Interrupted);
+                }
+                t => unreachable!("Unexpected token: {:?}", t),
+            }
+        }
+    }
+}
+
+/// Keeps streaming from `input`, reopening it with `open` whenever it is closed if `reconnect` is
+/// set. `name` identifies the input in the connection events.
+pub fn stream_with_reconnect<R, F>(
+    poll: &Poll,
+    mut input: R,
+    mut open: F,
+    name: &str,
+    session: &mut Session,
+    reconnect: bool,
+    interrupt: &Interrupt,
+) -> Result<(), Error>
+where
+    R: Source,
+    F: FnMut() -> io::Result<R>,
+{
+    loop {
+        match stream_packets(poll, input, session) {
+            Err(Error::PortClosed) if reconnect => (),
+            result => return result,
+        }
+
+        session.emit(
+            None,
+            EventKind::Connection {
+                name: name.to_string(),
+                connected: false,
+            },
+        );
+
+        // wait for the device to reappear, backing off so as not to hammer the OS
+        let mut backoff = MIN_RECONNECT_BACKOFF;
+        input = loop {
+            if interrupt.is_set() {
+                return Err(Error::Interrupted);
+            }
+
+            match open() {
+                Ok(input) => break input,
+                Err(_) => {
+                    thread::sleep(backoff);
+                    backoff = (backoff * 2).min(MAX_RECONNECT_BACKOFF);
+                }
+            }
+        };
+
+        session.emit(
+            None,
+            EventKind::Connection {
+                name: name.to_string(),
+                connected: true,
+            },
+        );
+    }
+}
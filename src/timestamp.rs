@@ -0,0 +1,200 @@
+use crate::decoder::Packet;
+use chrono::{
+    format::{Item, StrftimeItems},
+    DateTime, Local, SecondsFormat, Utc,
+};
+use std::cell::Cell;
+
+/// 24 hour format - YYYY-mm-DD HH:MM:SS.FFF
+pub const HOST_FORMAT: &str = "%Y-%m-%d %H:%M:%S%.3f";
+
+/// The number of timestamp bits carried by a global timestamp 1 packet
+const GTS1_BITS: u8 = 26;
+
+/// Tracks the target time given by the timestamp packets the target emits.
+///
+/// Local timestamps count the cycles of the ITM timestamp clock (the processor clock divided by
+/// the TSPrescale of ITM_TCR) since the previous local timestamp, and are summed into the target
+/// time. Global timestamps count a separate clock, so they are kept apart rather than mixed in.
+#[derive(Default)]
+pub struct TargetClock {
+    local: Option<u64>,
+    global: Option<u64>,
+}
+
+impl TargetClock {
+    /// Applies a timestamp packet, returning false if the packet is not a timestamp
+    pub fn update(&mut self, p: &Packet) -> bool {
+        match *p {
+            Packet::LocalTimestamp { delta, .. } => {
+                self.local = Some(self.local.unwrap_or(0) + u64::from(delta));
+            }
+            Packet::GlobalTimestamp1 { value, bits, .. } => {
+                let mask = (1u64 << bits) - 1;
+                self.global = Some((self.global.unwrap_or(0) & !mask) | u64::from(value));
+            }
+            Packet::GlobalTimestamp2 { value } => {
+                let mask = (1u64 << GTS1_BITS) - 1;
+                self.global = Some((self.global.unwrap_or(0) & mask) | value << GTS1_BITS);
+            }
+            _ => return false,
+        }
+        true
+    }
+
+    /// The target time in timestamp clock ticks, or `None` before the first local timestamp
+    pub fn ticks(&self) -> Option<u64> {
+        self.local
+    }
+
+    /// The global timestamp, or `None` before the first global timestamp packet
+    pub fn global(&self) -> Option<u64> {
+        self.global
+    }
+}
+
+/// Which clock to stamp decoded lines and events with
+#[derive(Clone, Copy, Default, PartialEq)]
+pub enum TimeSource {
+    #[default]
+    Host,
+    Target,
+    Both,
+}
+
+impl TimeSource {
+    pub fn parse(s: &str) -> Result<TimeSource, String> {
+        match s {
+            "host" => Ok(TimeSource::Host),
+            "target" => Ok(TimeSource::Target),
+            "both" => Ok(TimeSource::Both),
+            _ => Err(format!(
+                "Invalid time source: {} (expected host, target or both)",
+                s
+            )),
+        }
+    }
+}
+
+/// How to print the host time
+#[derive(Clone)]
+pub enum HostTime {
+    None,
+    /// Local time with microseconds
+    Local,
+    /// UTC with microseconds
+    Utc,
+    /// Local time as RFC 3339 with microseconds
+    Rfc3339,
+    /// A strftime format string applied to the local time
+    Custom(String),
+    /// Seconds since `start`
+    Elapsed {
+        start: DateTime<Local>,
+    },
+    /// Seconds since the previous line or event
+    Delta {
+        previous: Cell<Option<DateTime<Local>>>,
+    },
+}
+
+impl HostTime {
+    /// Accepts a style name or, if it contains a `%`, a custom strftime format
+    pub fn parse(s: &str) -> Result<HostTime, String> {
+        match s {
+            "none" => Ok(HostTime::None),
+            "local" => Ok(HostTime::Local),
+            "utc" => Ok(HostTime::Utc),
+            "rfc3339" => Ok(HostTime::Rfc3339),
+            "elapsed" => Ok(HostTime::Elapsed { start: Local::now() }),
+            "delta" => Ok(HostTime::Delta {
+                previous: Cell::new(None),
+            }),
+            _ if s.contains('%') => {
+                if StrftimeItems::new(s).any(|item| item == Item::Error) {
+                    Err(format!("Invalid strftime format: {}", s))
+                } else {
+                    Ok(HostTime::Custom(s.to_string()))
+                }
+            }
+            _ => Err(format!(
+                "Invalid timestamp: {} (expected none, local, utc, rfc3339, elapsed, delta or a strftime format such as %H:%M:%S%.6f)",
+                s
+            )),
+        }
+    }
+
+    fn format(&self, host: &DateTime<Local>) -> String {
+        match *self {
+            HostTime::None => String::new(),
+            HostTime::Local => host.format("%Y-%m-%d %H:%M:%S%.6f").to_string(),
+            HostTime::Utc => host
+                .with_timezone(&Utc)
+                .format("%Y-%m-%d %H:%M:%S%.6fZ")
+                .to_string(),
+            HostTime::Rfc3339 => host.to_rfc3339_opts(SecondsFormat::Micros, false),
+            HostTime::Custom(ref format) => host.format(format).to_string(),
+            HostTime::Elapsed { start } => format_seconds(*host - start, ""),
+            HostTime::Delta { ref previous } => {
+                let delta = previous.get().map(|p| *host - p).unwrap_or_default();
+                previous.set(Some(*host));
+                format_seconds(delta, "+")
+            }
+        }
+    }
+}
+
+// Formats a duration as seconds with microsecond precision, e.g. +1.000250
+fn format_seconds(duration: chrono::Duration, plus: &str) -> String {
+    let micros = duration.num_microseconds().unwrap_or(i64::MAX);
+    let sign = if micros < 0 { "-" } else { plus };
+    let micros = micros.unsigned_abs();
+    format!("{}{}.{:06}", sign, micros / 1_000_000, micros % 1_000_000)
+}
+
+impl Default for HostTime {
+    // the format printed before the timestamp could be configured
+    fn default() -> HostTime {
+        HostTime::Custom(HOST_FORMAT.to_string())
+    }
+}
+
+#[derive(Clone, Default)]
+pub struct TimeFormat {
+    pub source: TimeSource,
+    pub host: HostTime,
+    /// The frequency local timestamps count at in Hz, used to turn target ticks into seconds
+    pub tpiu_freq: Option<u64>,
+}
+
+impl TimeFormat {
+    /// Formats the prefix printed in front of each line or event, which may be empty
+    pub fn format(&self, host: &DateTime<Local>, target: Option<u64>) -> String {
+        let host = self.host.format(host);
+        match self.source {
+            TimeSource::Host => host,
+            TimeSource::Target => self.format_target(target),
+            TimeSource::Both if host.is_empty() => self.format_target(target),
+            TimeSource::Both => format!("{} {}", host, self.format_target(target)),
+        }
+    }
+
+    /// Converts target ticks into microseconds, if the trace clock frequency is known
+    pub fn target_micros(&self, target: Option<u64>) -> Option<u64> {
+        match (target, self.tpiu_freq) {
+            (Some(ticks), Some(freq)) => {
+                Some((u128::from(ticks) * 1_000_000 / u128::from(freq)) as u64)
+            }
+            _ => None,
+        }
+    }
+
+    fn format_target(&self, target: Option<u64>) -> String {
+        match (target, self.target_micros(target)) {
+            (_, Some(micros)) => format!("{}.{:06}s", micros / 1_000_000, micros % 1_000_000),
+            (Some(ticks), None) => format!("{}t", ticks),
+            // no timestamp packet has been received yet
+            (None, None) => "-".to_string(),
+        }
+    }
+}
@@ -0,0 +1,487 @@
+//! Strips the framing a TPIU adds in formatted (continuous) mode, where the output of several
+//! trace sources (e.g. ITM and ETM) is interleaved in 16 byte frames.
+//!
+//! Every even byte of a frame is either a data byte, whose least significant bit is kept in the
+//! last byte of the frame, or a change of trace source ID. Odd bytes are always data. Frames are
+//! aligned by full frame syncs (`FF FF FF 7F`), which may appear between any two frames.
+
+use crate::warning::{Stoppable, Warnings};
+use mio::{Evented, Poll, PollOpt, Ready, Token};
+use std::{
+    cell::{Cell, RefCell},
+    fs::File,
+    io::{self, BufWriter, Read, Write},
+    path::Path,
+    rc::Rc,
+};
+
+const FRAME_SIZE: usize = 16;
+
+/// A full frame sync, as the last four bytes read
+const FRAME_SYNC: u32 = 0xFF_FF_FF_7F;
+
+/// The trace ID the ITM usually has, as set in the TraceBusID field of ITM_TCR
+pub const DEFAULT_ITM_ID: u8 = 1;
+
+/// IDs 0 (null) and 0x70-0x7F (reserved, 0x7F being used for halfword syncs) carry no trace
+fn is_trace_id(id: u8) -> bool {
+    id != 0 && id < 0x70
+}
+
+/// Parses a trace source ID from 1 to 111 (0x6F)
+pub fn parse_id(s: &str) -> Result<u8, String> {
+    match s.parse::<u8>() {
+        Ok(id) if is_trace_id(id) => Ok(id),
+        _ => Err(format!(
+            "Invalid trace ID: {} (expected a number from 1 to 111)",
+            s
+        )),
+    }
+}
+
+/// Parses an `ID=path` entry naming the file to save the output of a trace source to
+pub fn parse_save(s: &str) -> Result<(u8, String), String> {
+    let mut parts = s.splitn(2, '=');
+    let id = parse_id(parts.next().unwrap_or_default())?;
+    match parts.next() {
+        Some(path) if !path.is_empty() => Ok((id, path.to_string())),
+        _ => Err(format!(
+            "Missing file name: {} (expected ID=path, e.g. 2=etm.bin)",
+            s
+        )),
+    }
+}
+
+/// Raw files the output of trace sources is saved to, e.g. ETM for an external decoder
+pub struct SourceFiles {
+    files: Vec<(u8, Stoppable<BufWriter<File>>)>,
+}
+
+impl SourceFiles {
+    pub fn create<P: AsRef<Path>>(
+        entries: &[(u8, P)],
+        warnings: Warnings,
+    ) -> io::Result<SourceFiles> {
+        let mut files = Vec::new();
+        for (id, path) in entries {
+            let path = path.as_ref();
+            let out = BufWriter::new(File::create(path)?);
+            let name = path.display().to_string();
+            files.push((*id, Stoppable::new(out, name, warnings.clone())));
+        }
+        Ok(SourceFiles { files })
+    }
+
+    fn write(&mut self, id: u8, bytes: &[u8]) {
+        for (file_id, file) in self.files.iter_mut() {
+            if *file_id == id {
+                file.write(|out| out.write_all(bytes));
+            }
+        }
+    }
+
+    pub fn flush(&mut self) {
+        for (_, file) in self.files.iter_mut() {
+            file.write(BufWriter::flush);
+        }
+    }
+}
+
+/// How to deformat the input, shared by every connection so that a reconnect carries on saving
+/// to the same files
+#[derive(Clone)]
+pub struct TpiuOptions {
+    pub itm_id: u8,
+    pub files: Option<Rc<RefCell<SourceFiles>>>,
+    /// Counts the bytes read by every deformatter, frames and all (see `Stats::wire_bytes`)
+    pub bytes_read: Rc<Cell<u64>>,
+}
+
+impl TpiuOptions {
+    pub fn deformatter<R>(&self, inner: R) -> Deformatter<R> {
+        let mut deformatter = Deformatter::new(inner, self.itm_id, self.files.clone());
+        deformatter.bytes_read = self.bytes_read.clone();
+        deformatter
+    }
+}
+
+/// Reads TPIU frames from `inner`, passing on the bytes of the ITM trace source and saving those
+/// of the sources that have a file
+pub struct Deformatter<R> {
+    inner: R,
+    itm_id: u8,
+    files: Option<Rc<RefCell<SourceFiles>>>,
+    // nothing is trusted until the first sync shows where the frames start
+    synced: bool,
+    last_four: u32,
+    frame: Vec<u8>,
+    /// The ID of the source the next data byte belongs to, which carries over between frames
+    id: Option<u8>,
+    /// Deformatted ITM bytes not read yet
+    itm: Vec<u8>,
+    itm_pos: usize,
+    /// Deformatted bytes to save, written out a chunk at a time
+    saved: Vec<(u8, u8)>,
+    bytes_read: Rc<Cell<u64>>,
+}
+
+impl<R> Deformatter<R> {
+    pub fn new(inner: R, itm_id: u8, files: Option<Rc<RefCell<SourceFiles>>>) -> Deformatter<R> {
+        Deformatter {
+            inner,
+            itm_id,
+            files,
+            synced: false,
+            last_four: 0,
+            frame: Vec::with_capacity(FRAME_SIZE),
+            id: None,
+            itm: Vec::new(),
+            itm_pos: 0,
+            saved: Vec::new(),
+            bytes_read: Rc::default(),
+        }
+    }
+
+    fn push(&mut self, byte: u8) {
+        self.last_four = (self.last_four << 8) | byte as u32;
+        if self.last_four == FRAME_SYNC {
+            // the sync is not part of a frame and whatever was read before it was misaligned
+            self.synced = true;
+            self.frame.clear();
+            return;
+        }
+        if !self.synced {
+            return;
+        }
+
+        self.frame.push(byte);
+        if self.frame.len() == FRAME_SIZE {
+            self.deformat_frame();
+            self.frame.clear();
+        }
+    }
+
+    fn deformat_frame(&mut self) {
+        let aux = self.frame[FRAME_SIZE - 1];
+        for i in 0..FRAME_SIZE / 2 {
+            let even = self.frame[i * 2];
+            // the last even byte is followed by the auxiliary byte rather than data
+            let odd = if i < FRAME_SIZE / 2 - 1 {
+                Some(self.frame[i * 2 + 1])
+            } else {
+                None
+            };
+            let aux_bit = (aux >> i) & 1;
+
+            if even & 1 == 1 {
+                // a change of ID, taking effect after the next byte if the auxiliary bit is set
+                let id = Some(even >> 1);
+                if aux_bit == 1 {
+                    self.data(odd);
+                    self.id = id;
+                } else {
+                    self.id = id;
+                    self.data(odd);
+                }
+            } else {
+                self.data(Some(even | aux_bit));
+                self.data(odd);
+            }
+        }
+    }
+
+    fn data(&mut self, byte: Option<u8>) {
+        let (id, byte) = match (self.id, byte) {
+            (Some(id), Some(byte)) => (id, byte),
+            _ => return,
+        };
+        if id == self.itm_id {
+            self.itm.push(byte);
+        }
+        if is_trace_id(id) && self.files.is_some() {
+            self.saved.push((id, byte));
+        }
+    }
+
+    fn save(&mut self) {
+        if let Some(ref files) = self.files {
+            let mut files = files.borrow_mut();
+            let mut run = Vec::new();
+            let mut run_id = None;
+            for &(id, byte) in self.saved.iter() {
+                if run_id != Some(id) {
+                    if let Some(run_id) = run_id {
+                        files.write(run_id, &run);
+                    }
+                    run.clear();
+                    run_id = Some(id);
+                }
+                run.push(byte);
+            }
+            if let Some(run_id) = run_id {
+                files.write(run_id, &run);
+            }
+        }
+        self.saved.clear();
+    }
+
+    fn flush_files(&mut self) {
+        if let Some(ref files) = self.files {
+            files.borrow_mut().flush();
+        }
+    }
+}
+
+impl<R: Read> Read for Deformatter<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        // a chunk of input may hold nothing but frames of other sources, so keep reading until
+        // there is some ITM data or the input has nothing more for now
+        while self.itm_pos == self.itm.len() {
+            self.itm.clear();
+            self.itm_pos = 0;
+
+            let mut chunk = [0u8; 1024];
+            let n = match self.inner.read(&mut chunk) {
+                Ok(0) => {
+                    self.flush_files();
+                    return Ok(0);
+                }
+                Ok(n) => {
+                    self.bytes_read.set(self.bytes_read.get() + n as u64);
+                    n
+                }
+                Err(e) => {
+                    // the input has gone quiet so it is a good time to get the files onto disk
+                    self.flush_files();
+                    return Err(e);
+                }
+            };
+
+            for &byte in chunk[..n].iter() {
+                self.push(byte);
+            }
+            self.save();
+        }
+
+        let n = buf.len().min(self.itm.len() - self.itm_pos);
+        buf[..n].copy_from_slice(&self.itm[self.itm_pos..self.itm_pos + n]);
+        self.itm_pos += n;
+        Ok(n)
+    }
+}
+
+impl<R: Evented> Evented for Deformatter<R> {
+    fn register(
+        &self,
+        poll: &Poll,
+        token: Token,
+        interest: Ready,
+        opts: PollOpt,
+    ) -> io::Result<()> {
+        self.inner.register(poll, token, interest, opts)
+    }
+
+    fn reregister(
+        &self,
+        poll: &Poll,
+        token: Token,
+        interest: Ready,
+        opts: PollOpt,
+    ) -> io::Result<()> {
+        self.inner.reregister(poll, token, interest, opts)
+    }
+
+    fn deregister(&self, poll: &Poll) -> io::Result<()> {
+        self.inner.deregister(poll)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{env, fs, process};
+
+    const SYNC: [u8; 4] = [0xff, 0xff, 0xff, 0x7f];
+
+    /// What goes in an even byte of a frame
+    enum Even {
+        /// A change to a source ID, taking effect after the next byte if `delayed` is set
+        Id(u8, bool),
+        Data(u8),
+    }
+
+    /// Builds a frame out of its eight even and seven odd bytes
+    fn frame(evens: [Even; 8], odds: [u8; 7]) -> Vec<u8> {
+        let mut frame = Vec::new();
+        let mut aux = 0;
+        for (i, even) in evens.iter().enumerate() {
+            let (byte, aux_bit) = match *even {
+                Even::Id(id, delayed) => (id << 1 | 1, delayed as u8),
+                Even::Data(byte) => (byte & !1, byte & 1),
+            };
+            frame.push(byte);
+            aux |= aux_bit << i;
+            if i < odds.len() {
+                frame.push(odds[i]);
+            }
+        }
+        frame.push(aux);
+        frame
+    }
+
+    /// A frame of ITM data only, starting with a change to the ITM ID
+    fn itm_frame(text: &[u8; 14]) -> Vec<u8> {
+        let t = text;
+        frame(
+            [
+                Even::Id(DEFAULT_ITM_ID, false),
+                Even::Data(t[1]),
+                Even::Data(t[3]),
+                Even::Data(t[5]),
+                Even::Data(t[7]),
+                Even::Data(t[9]),
+                Even::Data(t[11]),
+                Even::Data(t[13]),
+            ],
+            [t[0], t[2], t[4], t[6], t[8], t[10], t[12]],
+        )
+    }
+
+    /// Hands out `bytes` a few at a time, so that frames and syncs are split across reads
+    struct Trickle<'a>(&'a [u8]);
+
+    impl<'a> Read for Trickle<'a> {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let n = self.0.len().min(buf.len()).min(5);
+            buf[..n].copy_from_slice(&self.0[..n]);
+            self.0 = &self.0[n..];
+            Ok(n)
+        }
+    }
+
+    fn deformat<R: Read>(input: R, files: Option<Rc<RefCell<SourceFiles>>>) -> Vec<u8> {
+        let mut itm = Vec::new();
+        Deformatter::new(input, DEFAULT_ITM_ID, files)
+            .read_to_end(&mut itm)
+            .unwrap();
+        itm
+    }
+
+    #[test]
+    fn id_change_takes_effect_as_the_aux_bit_says() {
+        let mut input = SYNC.to_vec();
+        input.extend(frame(
+            [
+                Even::Id(1, false),
+                Even::Data(b'b'),
+                // the byte after a delayed change still belongs to ITM
+                Even::Id(2, true),
+                Even::Data(b'E'),
+                Even::Id(1, false),
+                Even::Data(b'f'),
+                // the byte after an immediate change belongs to the new ID
+                Even::Id(2, false),
+                Even::Data(b'I'),
+            ],
+            [b'a', b'c', b'd', b'F', b'e', b'g', b'H'],
+        ));
+        assert_eq!(deformat(&input[..], None), b"abcdefg");
+    }
+
+    #[test]
+    fn last_even_byte_has_no_odd_partner() {
+        let mut input = SYNC.to_vec();
+        input.extend(itm_frame(b"0123456789abcd"));
+        // an ID change in the last even byte applies to the first byte of the next frame
+        input.extend(frame(
+            [
+                Even::Data(b'e'),
+                Even::Data(b'g'),
+                Even::Data(b'i'),
+                Even::Data(b'k'),
+                Even::Data(b'm'),
+                Even::Data(b'o'),
+                Even::Data(b'q'),
+                Even::Id(2, true),
+            ],
+            [b'f', b'h', b'j', b'l', b'n', b'p', b'r'],
+        ));
+        input.extend(frame(
+            [
+                Even::Data(b'X'),
+                Even::Id(1, false),
+                Even::Data(b't'),
+                Even::Data(b'v'),
+                Even::Data(b'x'),
+                Even::Data(b'z'),
+                Even::Data(b'1'),
+                Even::Data(b'3'),
+            ],
+            [b'Y', b's', b'u', b'w', b'y', b'0', b'2'],
+        ));
+        assert_eq!(
+            deformat(&input[..], None),
+            &b"0123456789abcdefghijklmnopqrstuvwxyz0123"[..]
+        );
+    }
+
+    #[test]
+    fn syncs_between_frames() {
+        // bytes before the first sync can't be trusted to be aligned
+        let mut input = vec![0x12, 0x34, 0x56];
+        input.extend(&SYNC);
+        input.extend(itm_frame(b"0123456789abcd"));
+        input.extend(&SYNC);
+        input.extend(&SYNC);
+        input.extend(itm_frame(b"efghijklmnopqr"));
+        input.extend(&SYNC);
+        let expected = &b"0123456789abcdefghijklmnopqr"[..];
+        assert_eq!(deformat(&input[..], None), expected);
+        assert_eq!(deformat(Trickle(&input), None), expected);
+    }
+
+    #[test]
+    fn sources_are_saved_to_their_files() {
+        let dir = env::temp_dir().join(format!("serialitm-tpiu-{}", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let etm = dir.join("etm.bin");
+        let other = dir.join("other.bin");
+        let files = SourceFiles::create(&[(2, &etm), (3, &other)], Warnings::new()).unwrap();
+
+        let mut input = SYNC.to_vec();
+        input.extend(frame(
+            [
+                Even::Id(2, false),
+                Even::Data(b'c'),
+                Even::Id(3, false),
+                Even::Data(b'x'),
+                Even::Id(1, false),
+                Even::Data(b'i'),
+                Even::Id(2, false),
+                Even::Data(b'e'),
+            ],
+            [b'a', b'd', b'w', b'y', b'h', b't', b'f'],
+        ));
+        let itm = deformat(&input[..], Some(Rc::new(RefCell::new(files))));
+
+        assert_eq!(itm, b"hit");
+        assert_eq!(fs::read(&etm).unwrap(), b"acdfe");
+        assert_eq!(fs::read(&other).unwrap(), b"wxy");
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn counts_bytes_read() {
+        let tpiu = TpiuOptions {
+            itm_id: DEFAULT_ITM_ID,
+            files: None,
+            bytes_read: Rc::default(),
+        };
+        let mut input = SYNC.to_vec();
+        input.extend(itm_frame(b"0123456789abcd"));
+        let mut itm = Vec::new();
+        tpiu.deformatter(&input[..]).read_to_end(&mut itm).unwrap();
+        assert_eq!(itm.len(), 14);
+        assert_eq!(tpiu.bytes_read.get(), 20);
+    }
+}
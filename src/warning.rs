@@ -0,0 +1,92 @@
+//! Problems that don't stop decoding, such as an output file that can no longer be written.
+//! They are collected from wherever they happen, background threads included, for the
+//! [`Session`](crate::session::Session) to hand to its sink as events. Outputs are wrapped in
+//! a [`Stoppable`] so that a failed write is reported once and the output then left alone.
+
+use std::{
+    io, mem,
+    sync::{Arc, Mutex, MutexGuard},
+};
+
+/// A list of warning messages not reported yet, shared by everything that can add to it
+#[derive(Clone, Default)]
+pub struct Warnings {
+    messages: Arc<Mutex<Vec<String>>>,
+}
+
+impl Warnings {
+    pub fn new() -> Warnings {
+        Warnings::default()
+    }
+
+    pub fn push(&self, message: String) {
+        self.lock().push(message);
+    }
+
+    /// Takes the messages added since the last call
+    pub fn take(&self) -> Vec<String> {
+        mem::take(&mut *self.lock())
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Vec<String>> {
+        // pushing a message can't leave the list half updated, even in a thread that panicked
+        self.messages
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+/// An output that is written until a write to it fails, which is reported as a warning rather
+/// than stopping decoding (e.g. on a full disk)
+pub struct Stoppable<W> {
+    // `None` once a write has failed
+    inner: Option<W>,
+    /// Names the output in the warning, e.g. its path
+    name: String,
+    warnings: Warnings,
+}
+
+impl<W> Stoppable<W> {
+    pub fn new(inner: W, name: String, warnings: Warnings) -> Stoppable<W> {
+        Stoppable {
+            inner: Some(inner),
+            name,
+            warnings,
+        }
+    }
+
+    /// Takes the output that was just opened, or reports that it could not be and starts off
+    /// stopped
+    pub fn open(opened: io::Result<W>, name: String, warnings: Warnings) -> Stoppable<W> {
+        let inner = match opened {
+            Ok(inner) => Some(inner),
+            Err(e) => {
+                warnings.push(format!(
+                    "Failed to open \"{}\". Error: {}. Output to it stopped",
+                    name, e
+                ));
+                None
+            }
+        };
+        Stoppable {
+            inner,
+            name,
+            warnings,
+        }
+    }
+
+    /// Applies `write` to the output, unless an earlier write failed
+    pub fn write<F: FnOnce(&mut W) -> io::Result<()>>(&mut self, write: F) {
+        let result = match self.inner {
+            Some(ref mut inner) => write(inner),
+            None => return,
+        };
+        if let Err(e) = result {
+            self.warnings.push(format!(
+                "Failed to write \"{}\". Error: {}. Output to it stopped",
+                self.name, e
+            ));
+            self.inner = None;
+        }
+    }
+}